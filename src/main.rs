@@ -7,12 +7,15 @@
 //! All settings are read from a YAML config file.
 //! Default path: ~/.claude/cc-goto-work/config.yaml
 
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
+use flate2::read::GzDecoder;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
 use std::fs::{self, File, OpenOptions};
 use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
@@ -20,17 +23,55 @@ use std::time::{Duration, SystemTime, UNIX_EPOCH};
 // Constants
 // ============================================================================
 
+/// Env var that, when truthy, makes the hook allow every stop untouched
+const DISABLE_ENV_VAR: &str = "CC_GOTO_WORK_DISABLE";
+/// Env var that mirrors debug log entries to stderr, like `--debug`
+const DEBUG_ENV_VAR: &str = "CC_GOTO_WORK_DEBUG";
 /// Default config file path
 const DEFAULT_CONFIG_PATH: &str = "~/.claude/cc-goto-work/config.yaml";
-/// Read approximately last 10KB of transcript for efficiency
+/// Smallest tail window; anything less rarely holds a complete line
+const MIN_TAIL_READ_BYTES: u64 = 1024;
+/// Upper bound when widening the tail window to find a stop_reason (256KB)
+const MAX_TAIL_READ_BYTES: u64 = 256 * 1024;
+/// Maximum bytes kept from a single transcript line; the rest is discarded
+const MAX_LINE_BYTES: usize = 64 * 1024;
+/// Maximum bytes buffered while reassembling a multi-line JSON entry
+const MAX_MULTILINE_BYTES: usize = 8 * 1024;
+/// Maximum number of older rotated segments (`transcript.1.jsonl`, ...) merged in
+const MAX_ROTATED_SEGMENTS: u32 = 3;
+/// Leading bytes of a gzip stream
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+/// Default number of bytes read from the end of the transcript (10KB)
 const TAIL_READ_BYTES: u64 = 10 * 1024;
-/// Maximum number of transcript lines to send to AI
+/// Default maximum number of transcript lines to send to AI
 const AI_MAX_LINES: usize = 20;
+/// Default template for the reason sent back to Claude when blocking
+const DEFAULT_REASON_TEMPLATE: &str = "AI: {reason}";
 /// Default API request timeout in seconds
 const DEFAULT_TIMEOUT_SECONDS: u64 = 30;
+/// Default time to wait for the hook input on stdin
+const DEFAULT_INPUT_TIMEOUT_SECONDS: u64 = 5;
+/// Exit code for a blocked stop when `--exit-code` is set (2 is reserved by Claude Code)
+const EXIT_BLOCKED: i32 = 3;
 /// Debug log file name (written next to the executable when enabled)
 const DEBUG_LOG_FILENAME: &str = "cc-goto-work.log";
 
+// ============================================================================
+// Diagnostics
+// ============================================================================
+
+/// Set by `--quiet` to suppress stderr diagnostics
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Print a diagnostic line to stderr unless `--quiet` is set
+macro_rules! diag {
+    ($($arg:tt)*) => {
+        if !QUIET.load(Ordering::Relaxed) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
 // ============================================================================
 // CLI Arguments
 // ============================================================================
@@ -43,6 +84,147 @@ struct Args {
     /// Path to config file
     #[arg(short, long, default_value = DEFAULT_CONFIG_PATH)]
     config: String,
+
+    /// Save each hook input and transcript tail into this directory for later replay
+    #[arg(long, value_name = "DIR")]
+    record: Option<String>,
+
+    /// Check the emitted output against the Stop hook schema before printing (development aid)
+    #[arg(long)]
+    validate_output: bool,
+
+    /// Show the hook's intervention in the transcript view instead of suppressing it
+    #[arg(long)]
+    show_intervention: bool,
+
+    /// Emit single-letter output keys (d=decision, r=reason, c=continue, s=stopReason, q=suppressOutput).
+    /// Only for wrappers that decode them; Claude Code itself expects the full keys.
+    #[arg(long)]
+    terse: bool,
+
+    /// Append one JSON line per invocation to this file
+    #[arg(long, value_name = "FILE", env = "CC_GOTO_WORK_LOG")]
+    log: Option<String>,
+
+    /// Store one row per invocation in this SQLite database
+    #[cfg(feature = "sqlite")]
+    #[arg(long, value_name = "PATH")]
+    decisions_db: Option<String>,
+
+    /// Maintain a Prometheus textfile with per-decision counters at this path
+    #[arg(long, value_name = "PATH")]
+    metrics_file: Option<String>,
+
+    /// Allow the stop once this many consecutive interventions are found in the transcript
+    #[arg(long, value_name = "N")]
+    max_retries: Option<usize>,
+
+    /// Allow the stop without an AI check when a stop hook already blocked this turn,
+    /// unless the last transcript entry is a fresh API error
+    #[arg(long)]
+    once_per_turn: bool,
+
+    /// Mirror debug log entries to stderr (also enabled by a truthy CC_GOTO_WORK_DEBUG)
+    #[arg(short, long)]
+    debug: bool,
+
+    /// Suppress all stderr diagnostics (exit codes and stdout are unaffected)
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// Seconds to wait for the hook input on stdin (0 waits forever)
+    #[arg(long, value_name = "SECONDS", default_value_t = DEFAULT_INPUT_TIMEOUT_SECONDS)]
+    input_timeout: u64,
+
+    /// Reflect the decision in the exit code: 0 = stop allowed, 3 = stop blocked.
+    /// Errors always exit 4 = config, 5 = stdin, 6 = input parse, 7 = transcript, 8 = serialize.
+    /// For wrapper scripts only: Claude Code ignores stdout JSON on a nonzero exit.
+    #[arg(long)]
+    exit_code: bool,
+
+    /// Bytes to read from the end of the transcript, e.g. 64k or 1m (overrides config `tail_bytes`)
+    #[arg(long, value_name = "SIZE", value_parser = parse_byte_size, global = true)]
+    tail_bytes: Option<u64>,
+
+    /// Character encoding of the transcript file
+    #[arg(long, value_enum, default_value_t = TranscriptEncoding::Utf8, global = true)]
+    transcript_encoding: TranscriptEncoding,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+/// Supported transcript encodings
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum TranscriptEncoding {
+    Utf8,
+    Utf16le,
+    /// ISO-8859-1 (decoded as Windows-1252)
+    Latin1,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Validate the runtime environment and configuration
+    Doctor,
+    /// Run built-in sample transcripts through the hook's decision path and sample replies through the response parser
+    Selftest,
+    /// Print the transcript tail lines as the hook sees them
+    DumpLines {
+        /// Transcript file to read
+        file: String,
+    },
+    /// Run the AI check on a transcript file and print the decision without the hook protocol
+    Check {
+        /// Transcript file to check
+        file: String,
+        /// Treat the check as if a stop hook already blocked this turn (see --once-per-turn)
+        #[arg(long)]
+        stop_hook_active: bool,
+    },
+}
+
+impl Args {
+    /// Whether debug entries go to stderr, from `--debug` or `CC_GOTO_WORK_DEBUG`.
+    /// `--quiet` wins: it silences the mirror like every other diagnostic.
+    fn debug_enabled(&self) -> bool {
+        (self.debug || env_flag(DEBUG_ENV_VAR)) && !self.quiet
+    }
+}
+
+/// Whether an env var is set to a truthy value. Read leniently rather than through
+/// clap, so an unexpected value never becomes a usage error (exit 2), which
+/// Claude Code would treat as a blocking error.
+fn env_flag(name: &str) -> bool {
+    std::env::var(name).is_ok_and(|v| is_truthy(&v))
+}
+
+/// `1`, `true`, `yes` or `on`, ignoring case and surrounding whitespace
+fn is_truthy(value: &str) -> bool {
+    matches!(value.trim().to_ascii_lowercase().as_str(), "1" | "true" | "yes" | "on")
+}
+
+/// Parse a byte size with an optional `k`/`kb`/`m`/`mb` suffix (powers of 1024)
+fn parse_byte_size(value: &str) -> Result<u64, String> {
+    let lower = value.trim().to_ascii_lowercase();
+    let (number, multiplier) = if let Some(n) = lower.strip_suffix("kb").or_else(|| lower.strip_suffix('k')) {
+        (n, 1024)
+    } else if let Some(n) = lower.strip_suffix("mb").or_else(|| lower.strip_suffix('m')) {
+        (n, 1024 * 1024)
+    } else {
+        (lower.strip_suffix('b').unwrap_or(&lower), 1)
+    };
+    number
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid size `{}` (expected e.g. 10240, 64k, 1m)", value))
+}
+
+/// Pick the CLI tail size over the configured one, clamped to `MIN_TAIL_READ_BYTES`
+fn effective_tail_bytes(cli: Option<u64>, configured: u64) -> u64 {
+    cli.unwrap_or(configured).max(MIN_TAIL_READ_BYTES)
 }
 
 // ============================================================================
@@ -63,6 +245,16 @@ struct Config {
     /// Enable debug logging to a file alongside the executable (optional, default: false)
     #[serde(default)]
     debug: bool,
+    /// Bytes to read from the end of the transcript (optional, default: 10240)
+    #[serde(default = "default_tail_bytes")]
+    tail_bytes: u64,
+    /// Maximum number of transcript lines sent to the models (optional, default: 20)
+    #[serde(default = "default_max_lines")]
+    max_lines: usize,
+    /// Template for the reason sent to Claude when blocking; `{reason}` is replaced
+    /// with the vote summary and model explanation (optional, default: "AI: {reason}")
+    #[serde(default)]
+    reason_template: Option<String>,
 }
 
 /// Configuration for a single API provider
@@ -80,6 +272,14 @@ fn default_timeout() -> u64 {
     DEFAULT_TIMEOUT_SECONDS
 }
 
+fn default_tail_bytes() -> u64 {
+    TAIL_READ_BYTES
+}
+
+fn default_max_lines() -> usize {
+    AI_MAX_LINES
+}
+
 impl Config {
     fn load(path: &PathBuf) -> Result<Self, Box<dyn std::error::Error>> {
         let content = fs::read_to_string(path)?;
@@ -98,6 +298,20 @@ impl Config {
                 "no models configured: each provider must have at least one model",
             )));
         }
+        // Zero lines would send the models an empty transcript and fail every check
+        if config.max_lines == 0 {
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "`max_lines` must be at least 1",
+            )));
+        }
+        // `--max-retries` finds earlier interventions by the vote summary inside `{reason}`
+        if config.reason_template.as_deref().is_some_and(|t| !t.contains("{reason}")) {
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "`reason_template` must contain the `{reason}` placeholder",
+            )));
+        }
         Ok(config)
     }
 }
@@ -106,11 +320,12 @@ impl Config {
 // Debug Logging
 // ============================================================================
 
-/// Debug logger that writes to a file alongside the executable
+/// Debug logger that writes to a file alongside the executable, and optionally to stderr
 struct DebugLogger {
     enabled: bool,
     log_path: PathBuf,
     run_id: String,
+    stderr: bool,
 }
 
 impl DebugLogger {
@@ -123,11 +338,12 @@ impl DebugLogger {
             enabled,
             log_path,
             run_id,
+            stderr: false,
         };
 
         if logger.enabled {
             if let Err(e) = logger.touch() {
-                eprintln!(
+                diag!(
                     "Warning: debug enabled but failed to create log file at {:?}: {}",
                     logger.log_path, e
                 );
@@ -138,6 +354,12 @@ impl DebugLogger {
         logger
     }
 
+    /// Also mirror every log entry to stderr (never stdout, which carries the hook output)
+    fn with_stderr(mut self, stderr: bool) -> Self {
+        self.stderr = stderr;
+        self
+    }
+
     /// Log path accessor for display purposes
     fn path(&self) -> &PathBuf {
         &self.log_path
@@ -162,7 +384,7 @@ impl DebugLogger {
 
     /// Write a log entry. Silently ignores errors to avoid affecting main flow.
     fn log(&self, level: &str, message: impl AsRef<str>) {
-        if !self.enabled {
+        if !self.enabled && !self.stderr {
             return;
         }
 
@@ -170,6 +392,13 @@ impl DebugLogger {
         let msg = message.as_ref();
         let line = format!("{} [{}] [{}] {}\n", ts, self.run_id, level, msg);
 
+        if self.stderr {
+            eprint!("{}", line);
+        }
+        if !self.enabled {
+            return;
+        }
+
         if let Ok(mut file) = OpenOptions::new()
             .create(true)
             .append(true)
@@ -236,21 +465,169 @@ struct HookInput {
     stop_hook_active: Option<bool>,
 }
 
+/// Read all of `reader` (stdin in production), giving up after `timeout_secs` seconds (0 waits forever)
+fn read_input<R: Read + Send + 'static>(mut reader: R, timeout_secs: u64) -> Result<String, HookError> {
+    if timeout_secs == 0 {
+        let mut input_str = String::new();
+        reader.read_to_string(&mut input_str).map_err(HookError::StdinRead)?;
+        return Ok(input_str);
+    }
+
+    // The reader thread is left behind on timeout; the process exits right after
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let mut input_str = String::new();
+        let result = reader.read_to_string(&mut input_str).map(|_| input_str);
+        let _ = tx.send(result);
+    });
+
+    match rx.recv_timeout(Duration::from_secs(timeout_secs)) {
+        Ok(result) => result.map_err(HookError::StdinRead),
+        Err(_) => Err(HookError::StdinRead(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("no hook input on stdin after {}s (see --input-timeout)", timeout_secs),
+        ))),
+    }
+}
+
+/// Parse the hook input, rejecting malformed JSON and JSON that isn't an object
+fn parse_hook_input(input_str: &str) -> Result<HookInput, HookError> {
+    let value: serde_json::Value = serde_json::from_str(input_str)
+        .map_err(|e| HookError::InputParse(format!("malformed hook input JSON on stdin: {}", e)))?;
+    if !value.is_object() {
+        return Err(HookError::InputParse(format!(
+            "hook input on stdin must be a JSON object, got: {}",
+            truncate_for_log(input_str.trim(), 100)
+        )));
+    }
+    serde_json::from_value(value).map_err(|e| HookError::InputParse(format!("invalid hook input on stdin: {}", e)))
+}
+
 /// Output to control Claude's behavior
 #[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
 struct HookOutput {
     decision: String,
     reason: String,
+    /// Whether Claude should keep running after the hook (omitted when None)
+    #[serde(rename = "continue", skip_serializing_if = "Option::is_none")]
+    continue_session: Option<bool>,
+    /// Message shown to the user when `continue` is false (omitted when None)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop_reason: Option<String>,
+    /// Hide the hook's output from the transcript view (omitted when None)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    suppress_output: Option<bool>,
+}
+
+impl HookOutput {
+    /// Serialize to JSON, optionally checking the result against the Stop hook schema
+    /// and renaming keys to their single-letter forms (see `TERSE_KEYS`)
+    fn to_json(&self, validate: bool, terse: bool) -> Result<String, HookError> {
+        let mut value = serde_json::to_value(self).map_err(|e| HookError::Serialize(e.into()))?;
+        if terse {
+            value = rename_keys(value, &TERSE_KEYS);
+        }
+        if validate {
+            // Validate what is actually emitted: terse output must decode to a valid one
+            let emitted = if terse { decode_terse(value.clone()) } else { value.clone() };
+            validate_hook_output(&emitted)
+                .map_err(|e| HookError::Serialize(format!("invalid hook output: {}", e).into()))?;
+        }
+        serde_json::to_string(&value).map_err(|e| HookError::Serialize(e.into()))
+    }
+}
+
+/// Mapping of full output keys to the single-letter keys emitted by `--terse`
+const TERSE_KEYS: [(&str, &str); 5] = [
+    ("decision", "d"),
+    ("reason", "r"),
+    ("continue", "c"),
+    ("stopReason", "s"),
+    ("suppressOutput", "q"),
+];
+
+/// Restore the full keys of `--terse` output, for wrappers consuming it
+fn decode_terse(value: serde_json::Value) -> serde_json::Value {
+    let mapping: Vec<(&str, &str)> = TERSE_KEYS.iter().map(|(full, short)| (*short, *full)).collect();
+    rename_keys(value, &mapping)
+}
+
+/// Rename top-level object keys according to `(from, to)` pairs
+fn rename_keys(value: serde_json::Value, mapping: &[(&str, &str)]) -> serde_json::Value {
+    match value {
+        serde_json::Value::Object(obj) => serde_json::Value::Object(
+            obj.into_iter()
+                .map(|(key, v)| {
+                    let renamed = mapping
+                        .iter()
+                        .find(|(from, _)| *from == key)
+                        .map(|(_, to)| to.to_string())
+                        .unwrap_or(key);
+                    (renamed, v)
+                })
+                .collect(),
+        ),
+        other => other,
+    }
+}
+
+/// Build the output that blocks the stop and sends `reason` back to Claude
+fn block_output(reason: &str, config: &Config, args: &Args) -> HookOutput {
+    let template = config.reason_template.as_deref().unwrap_or(DEFAULT_REASON_TEMPLATE);
+    HookOutput {
+        decision: "block".to_string(),
+        reason: template.replace("{reason}", reason),
+        continue_session: None,
+        stop_reason: None,
+        suppress_output: if args.show_intervention { None } else { Some(true) },
+    }
+}
+
+/// Check a serialized hook output against the Claude Code Stop hook contract
+fn validate_hook_output(value: &serde_json::Value) -> Result<(), String> {
+    let obj = value.as_object().ok_or("output is not a JSON object")?;
+    match obj.get("decision") {
+        Some(serde_json::Value::String(d)) if d == "block" => {}
+        Some(other) => return Err(format!("`decision` must be \"block\" for a Stop hook, got {}", other)),
+        None => return Err("missing `decision`".to_string()),
+    }
+    match obj.get("reason") {
+        Some(serde_json::Value::String(r)) if !r.is_empty() => {}
+        Some(serde_json::Value::String(_)) => return Err("`reason` must not be empty".to_string()),
+        Some(other) => return Err(format!("`reason` must be a string, got {}", other)),
+        None => return Err("missing `reason`".to_string()),
+    }
+
+    for (key, v) in obj {
+        let well_typed = match key.as_str() {
+            "decision" | "reason" => true,
+            "continue" | "suppressOutput" => v.is_boolean(),
+            "stopReason" | "systemMessage" => v.is_string(),
+            _ => return Err(format!("unknown key `{}`", key)),
+        };
+        if !well_typed {
+            return Err(format!("`{}` has the wrong type: {}", key, v));
+        }
+    }
+    Ok(())
 }
 
 /// A parsed line from the transcript
 #[derive(Debug, Clone)]
 struct TranscriptLine {
-    #[allow(dead_code)]
     raw: String,
     json: Option<serde_json::Value>,
 }
 
+/// Lines read from the end of one transcript file
+#[derive(Default)]
+struct TranscriptTail {
+    lines: Vec<TranscriptLine>,
+    /// Whether the lines start at the beginning of the file
+    complete: bool,
+}
+
 // ============================================================================
 // AI Response
 // ============================================================================
@@ -266,32 +643,213 @@ struct AiCheckResponse {
 // Transcript Reading
 // ============================================================================
 
-fn read_transcript_tail(path: &PathBuf) -> Result<Vec<TranscriptLine>, Box<dyn std::error::Error>> {
+/// Read the transcript tail, reaching back into rotated segments when the
+/// newest one holds no stop_reason yet. Segments follow the logrotate naming
+/// (`transcript.jsonl` is newest, then `transcript.1.jsonl`, `transcript.2.jsonl`)
+/// and their tails are concatenated oldest first. An older segment is only
+/// prepended when everything read so far starts at the beginning of its file,
+/// so the merged view never has a gap in the middle.
+fn read_transcript_tail(
+    path: &Path,
+    tail_bytes: u64,
+    encoding: TranscriptEncoding,
+) -> Result<Vec<TranscriptLine>, Box<dyn std::error::Error>> {
+    let mut tail = read_segment_tail(path, tail_bytes, encoding)?;
+
+    for n in 1..=MAX_ROTATED_SEGMENTS {
+        if !tail.complete || tail.lines.iter().any(has_stop_reason) {
+            break;
+        }
+        let Some(segment) = rotated_segment_path(path, n).filter(|p| p.is_file()) else {
+            break;
+        };
+        let mut older = read_segment_tail(&segment, tail_bytes, encoding)?;
+        older.lines.append(&mut tail.lines);
+        tail = older;
+    }
+
+    Ok(tail.lines)
+}
+
+/// Path of the `n`th rotated segment: `transcript.jsonl` -> `transcript.<n>.jsonl`
+fn rotated_segment_path(path: &Path, n: u32) -> Option<PathBuf> {
+    let stem = path.file_stem()?.to_str()?;
+    let rotated = match path.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) => format!("{}.{}.{}", stem, n, ext),
+        None => format!("{}.{}", stem, n),
+    };
+    Some(path.with_file_name(rotated))
+}
+
+/// Read the tail of a single transcript file
+fn read_segment_tail(
+    path: &Path,
+    tail_bytes: u64,
+    encoding: TranscriptEncoding,
+) -> Result<TranscriptTail, Box<dyn std::error::Error>> {
     let mut file = match File::open(path) {
         Ok(f) => f,
-        Err(_) => return Ok(Vec::new()),
+        Err(_) => return Ok(TranscriptTail::default()),
     };
 
-    let file_len = file.metadata()?.len();
-    if file_len == 0 {
-        return Ok(Vec::new());
+    let metadata = file.metadata()?;
+    if metadata.is_dir() {
+        diag!("Warning: transcript path {:?} is a directory, allowing stop", path);
+        return Ok(TranscriptTail::default());
+    }
+    let compressed = path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("gz"))
+        || (metadata.is_file() && has_gzip_magic(&mut file)?);
+    if compressed {
+        // Compressed files can't be tailed by seeking; decompress and keep the tail
+        return Ok(read_stream_tail(decoding_reader(GzDecoder::new(file), encoding)?, tail_bytes));
+    }
+    if !metadata.is_file() {
+        // FIFOs, /dev/stdin and character devices can't seek and report no length
+        return Ok(read_stream_tail(decoding_reader(file, encoding)?, tail_bytes));
+    }
+
+    Ok(read_seekable_tail(&mut file, tail_bytes, encoding)?)
+}
+
+/// Read the tail of any seekable source as transcript lines. A large final turn
+/// can push its stop_reason out of the tail, so the window is widened until one
+/// is visible or `MAX_TAIL_READ_BYTES` is reached.
+fn read_seekable_tail<R: Read + Seek>(
+    reader: &mut R,
+    tail_bytes: u64,
+    encoding: TranscriptEncoding,
+) -> io::Result<TranscriptTail> {
+    let len = reader.seek(SeekFrom::End(0))?;
+    if len == 0 {
+        return Ok(TranscriptTail {
+            lines: Vec::new(),
+            complete: true,
+        });
+    }
+
+    let mut window = tail_bytes;
+    loop {
+        let lines = read_window(reader, len, window, encoding)?;
+        let exhausted = window >= len || window >= MAX_TAIL_READ_BYTES;
+        if exhausted || lines.iter().any(has_stop_reason) {
+            return Ok(TranscriptTail {
+                lines,
+                complete: window >= len,
+            });
+        }
+        window = (window.max(1) * 2).min(MAX_TAIL_READ_BYTES);
     }
+}
 
-    let (start_pos, drop_first_line) = if file_len <= TAIL_READ_BYTES {
+/// Read the last `window` bytes of a seekable source of `len` bytes, dropping
+/// the first (partial) line when starting mid-stream
+fn read_window<R: Read + Seek>(
+    reader: &mut R,
+    len: u64,
+    window: u64,
+    encoding: TranscriptEncoding,
+) -> io::Result<Vec<TranscriptLine>> {
+    let (mut start_pos, drop_first_line) = if len <= window {
         (0, false)
     } else {
-        (file_len - TAIL_READ_BYTES, true)
+        (len - window, true)
+    };
+    if encoding == TranscriptEncoding::Utf16le {
+        // Stay aligned to UTF-16 code units
+        start_pos &= !1;
+    }
+
+    reader.seek(SeekFrom::Start(start_pos))?;
+    Ok(parse_transcript_lines(
+        BufReader::new(decoding_reader(reader, encoding)?),
+        drop_first_line,
+    ))
+}
+
+/// The `stop_reason` of the most recent entry that has one
+fn last_stop_reason(lines: &[TranscriptLine]) -> Option<&str> {
+    lines.iter().rev().find_map(|line| {
+        line.json
+            .as_ref()
+            .and_then(|json| json.pointer("/message/stop_reason"))
+            .and_then(|v| v.as_str())
+    })
+}
+
+/// Whether a line carries a `message.stop_reason`
+fn has_stop_reason(line: &TranscriptLine) -> bool {
+    line.json
+        .as_ref()
+        .and_then(|json| json.pointer("/message/stop_reason"))
+        .is_some_and(|v| v.is_string())
+}
+
+/// Wrap a reader so it yields UTF-8. Non-UTF-8 input is read fully and transcoded.
+fn decoding_reader<'a, R: Read + 'a>(mut reader: R, encoding: TranscriptEncoding) -> io::Result<Box<dyn Read + 'a>> {
+    let encoding = match encoding {
+        TranscriptEncoding::Utf8 => return Ok(Box::new(reader)),
+        TranscriptEncoding::Utf16le => encoding_rs::UTF_16LE,
+        TranscriptEncoding::Latin1 => encoding_rs::WINDOWS_1252,
     };
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+    let (decoded, _, _) = encoding.decode(&bytes);
+    Ok(Box::new(io::Cursor::new(decoded.into_owned().into_bytes())))
+}
+
+/// Check for the gzip magic bytes, leaving the file positioned at the start
+fn has_gzip_magic(file: &mut File) -> io::Result<bool> {
+    let mut magic = [0u8; 2];
+    let matched = file.read_exact(&mut magic).is_ok() && magic == GZIP_MAGIC;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(matched)
+}
+
+/// Read a non-seekable stream to the end, keeping only the last `tail_bytes` worth of lines
+fn read_stream_tail<R: Read>(reader: R, tail_bytes: u64) -> TranscriptTail {
+    let mut reader = BufReader::new(reader);
+    let mut window: VecDeque<String> = VecDeque::new();
+    let mut window_bytes = 0u64;
+    let mut complete = true;
+
+    loop {
+        let mut line = String::new();
+        match read_capped_line(&mut reader, &mut line) {
+            Ok(0) => break,
+            Ok(_) => {
+                // Account decoded lengths on both push and pop: the bytes consumed
+                // differ once a line is capped or lossily decoded
+                window_bytes += line.len() as u64;
+                window.push_back(line);
+                while window_bytes > tail_bytes && window.len() > 1 {
+                    if let Some(dropped) = window.pop_front() {
+                        window_bytes -= dropped.len() as u64;
+                        complete = false;
+                    }
+                }
+            }
+            Err(_) => break,
+        }
+    }
 
-    file.seek(SeekFrom::Start(start_pos))?;
+    let tail: String = window.into_iter().collect();
+    TranscriptTail {
+        lines: parse_transcript_lines(tail.as_bytes(), false),
+        complete,
+    }
+}
 
-    let mut reader = BufReader::new(file);
+/// Split a reader into transcript lines, optionally dropping the first (partial) line.
+/// Pretty-printed objects spanning several lines are reassembled, up to `MAX_MULTILINE_BYTES`.
+fn parse_transcript_lines<R: BufRead>(mut reader: R, drop_first_line: bool) -> Vec<TranscriptLine> {
     let mut lines = Vec::new();
+    let mut pending: Vec<String> = Vec::new();
+    let mut pending_bytes = 0usize;
     let mut first_line = true;
 
     loop {
         let mut line = String::new();
-        match reader.read_line(&mut line) {
+        match read_capped_line(&mut reader, &mut line) {
             Ok(0) => break,
             Ok(_) => {
                 if first_line && drop_first_line {
@@ -305,7 +863,29 @@ fn read_transcript_tail(path: &PathBuf) -> Result<Vec<TranscriptLine>, Box<dyn s
                     continue;
                 }
 
-                let json = serde_json::from_str::<serde_json::Value>(trimmed).ok();
+                if !pending.is_empty() {
+                    pending_bytes += trimmed.len();
+                    pending.push(trimmed.to_string());
+                    let joined = pending.join("\n");
+                    if let Some(json) = parse_transcript_json(&joined) {
+                        lines.push(TranscriptLine { raw: joined, json: Some(json) });
+                        pending.clear();
+                        pending_bytes = 0;
+                    } else if pending_bytes > MAX_MULTILINE_BYTES {
+                        flush_pending_lines(&mut pending, &mut lines);
+                        pending_bytes = 0;
+                    }
+                    continue;
+                }
+
+                let json = parse_transcript_json(trimmed);
+                if json.is_none() && trimmed.starts_with('{') && brace_depth(trimmed) > 0 {
+                    // Likely the first line of a pretty-printed object
+                    pending_bytes = trimmed.len();
+                    pending.push(trimmed.to_string());
+                    continue;
+                }
+
                 lines.push(TranscriptLine {
                     raw: trimmed.to_string(),
                     json,
@@ -315,15 +895,206 @@ fn read_transcript_tail(path: &PathBuf) -> Result<Vec<TranscriptLine>, Box<dyn s
         }
     }
 
-    Ok(lines)
+    flush_pending_lines(&mut pending, &mut lines);
+    lines
+}
+
+/// Give up on reassembling a multi-line object and keep its lines individually
+fn flush_pending_lines(pending: &mut Vec<String>, lines: &mut Vec<TranscriptLine>) {
+    for raw in pending.drain(..) {
+        let json = parse_transcript_json(&raw);
+        lines.push(TranscriptLine { raw, json });
+    }
+}
+
+/// Net count of unclosed `{` outside of string literals
+fn brace_depth(text: &str) -> i64 {
+    let mut depth = 0i64;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for c in text.chars() {
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            _ => {}
+        }
+    }
+
+    depth
+}
+
+/// Read one line into `line` as lossy UTF-8, keeping at most `MAX_LINE_BYTES` and discarding the rest.
+/// Returns the number of bytes consumed from the reader (0 at EOF).
+fn read_capped_line<R: BufRead>(reader: &mut R, line: &mut String) -> io::Result<usize> {
+    let mut bytes = Vec::new();
+    let mut consumed = 0usize;
+
+    loop {
+        let available = reader.fill_buf()?;
+        if available.is_empty() {
+            break;
+        }
+        let (chunk_len, done) = match available.iter().position(|&b| b == b'\n') {
+            Some(i) => (i + 1, true),
+            None => (available.len(), false),
+        };
+        let room = MAX_LINE_BYTES.saturating_sub(bytes.len());
+        bytes.extend_from_slice(&available[..chunk_len.min(room)]);
+        reader.consume(chunk_len);
+        consumed += chunk_len;
+        if done {
+            break;
+        }
+    }
+
+    // Don't leave a multi-byte character cut in half by the cap
+    if consumed > bytes.len() {
+        if let Err(e) = std::str::from_utf8(&bytes) {
+            if e.error_len().is_none() {
+                bytes.truncate(e.valid_up_to());
+            }
+        }
+    }
+
+    // Decode lossily: a seek into the middle of a multi-byte character (or any
+    // stray invalid byte) must not abort reading the rest of the tail
+    line.push_str(&String::from_utf8_lossy(&bytes));
+    Ok(consumed)
+}
+
+/// Parse a transcript line as JSON, retrying with non-finite numbers
+/// (`NaN`, `Infinity`, `-Infinity`) replaced by `null` if the strict parse fails
+fn parse_transcript_json(text: &str) -> Option<serde_json::Value> {
+    if let Ok(value) = serde_json::from_str::<serde_json::Value>(text) {
+        return Some(value);
+    }
+    let sanitized = replace_non_finite_numbers(text)?;
+    serde_json::from_str::<serde_json::Value>(&sanitized).ok()
+}
+
+/// Replace bare non-finite number tokens outside of string literals with `null`.
+/// Returns None if nothing was replaced.
+fn replace_non_finite_numbers(text: &str) -> Option<String> {
+    const TOKENS: [&str; 3] = ["-Infinity", "Infinity", "NaN"];
+
+    let mut result = String::with_capacity(text.len());
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut replaced = false;
+    let mut i = 0;
+
+    while i < text.len() {
+        let rest = &text[i..];
+        let c = rest.chars().next()?;
+
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+            }
+        } else if c == '"' {
+            in_string = true;
+        } else if let Some(token) = TOKENS.iter().find(|t| rest.starts_with(**t)) {
+            result.push_str("null");
+            i += token.len();
+            replaced = true;
+            continue;
+        }
+
+        result.push(c);
+        i += c.len_utf8();
+    }
+
+    if replaced {
+        Some(result)
+    } else {
+        None
+    }
+}
+
+// ============================================================================
+// Intervention Counting
+// ============================================================================
+
+/// Count our own block interventions since the last genuine user prompt.
+/// Only user-role text (where Claude Code puts stop hook feedback) is considered, so
+/// tool output that merely quotes a vote summary (e.g. our own `--log`) never counts.
+/// Tool results and assistant turns between interventions don't break the streak.
+fn count_prior_interventions(lines: &[TranscriptLine]) -> usize {
+    let mut count = 0;
+    for json in lines.iter().rev().filter_map(|line| line.json.as_ref()) {
+        let Some(text) = user_text(json) else {
+            continue;
+        };
+        if contains_vote_summary(&text) {
+            count += 1;
+        } else {
+            break;
+        }
+    }
+    count
+}
+
+/// Whether text contains the `vote: N/M continue` summary that every block reason carries
+/// (config load rejects a `reason_template` that would drop it)
+fn contains_vote_summary(text: &str) -> bool {
+    text.match_indices("vote: ").any(|(i, marker)| {
+        let rest = &text[i + marker.len()..];
+        let Some((votes, _)) = rest.split_once(" continue") else {
+            return false;
+        };
+        votes
+            .split_once('/')
+            .is_some_and(|(n, m)| {
+                !n.is_empty()
+                    && !m.is_empty()
+                    && n.chars().all(|c| c.is_ascii_digit())
+                    && m.chars().all(|c| c.is_ascii_digit())
+            })
+    })
+}
+
+/// Text of a user-role entry (plain string or `text` blocks); None for tool results
+/// and other entry types
+fn user_text(json: &serde_json::Value) -> Option<String> {
+    if json.get("type").and_then(|v| v.as_str()) != Some("user") {
+        return None;
+    }
+    match json.pointer("/message/content") {
+        Some(serde_json::Value::String(text)) => Some(text.clone()),
+        Some(serde_json::Value::Array(blocks)) => {
+            let texts: Vec<&str> = blocks
+                .iter()
+                .filter(|b| b.get("type").and_then(|t| t.as_str()) == Some("text"))
+                .filter_map(|b| b.get("text").and_then(|t| t.as_str()))
+                .collect();
+            (!texts.is_empty()).then(|| texts.join("\n"))
+        }
+        _ => None,
+    }
 }
 
 // ============================================================================
 // Transcript Formatting
 // ============================================================================
 
-fn format_transcript_for_ai(lines: &[TranscriptLine]) -> String {
-    let recent_lines: Vec<_> = lines.iter().rev().take(AI_MAX_LINES).collect();
+fn format_transcript_for_ai(lines: &[TranscriptLine], max_lines: usize) -> String {
+    let recent_lines: Vec<_> = lines.iter().rev().take(max_lines).collect();
     let mut result = String::new();
 
     for line in recent_lines.into_iter().rev() {
@@ -677,7 +1448,7 @@ async fn check_with_ai(lines: &[TranscriptLine], config: &Config, logger: &Debug
         return None;
     }
 
-    let transcript_text = format_transcript_for_ai(lines);
+    let transcript_text = format_transcript_for_ai(lines, config.max_lines);
     if transcript_text.is_empty() {
         logger.log("WARN", "empty transcript after formatting; skipping ai check");
         return None;
@@ -704,6 +1475,7 @@ async fn check_with_ai(lines: &[TranscriptLine], config: &Config, logger: &Debug
         enabled: logger.enabled,
         log_path: logger.log_path.clone(),
         run_id: logger.run_id.clone(),
+        stderr: logger.stderr,
     });
 
     // Spawn concurrent tasks for all models
@@ -813,93 +1585,993 @@ async fn check_with_ai(lines: &[TranscriptLine], config: &Config, logger: &Debug
 }
 
 // ============================================================================
-// Path Expansion
+// Decision
 // ============================================================================
 
-fn expand_path(path: &str) -> PathBuf {
-    if path.starts_with("~/") || path.starts_with("~\\") {
-        if let Some(home) = dirs_next::home_dir() {
-            return home.join(&path[2..]);
+/// Options and switches that can settle a stop without the AI check
+struct DecisionPolicy {
+    /// `CC_GOTO_WORK_DISABLE` kill switch
+    disabled: bool,
+    max_retries: Option<usize>,
+    once_per_turn: bool,
+}
+
+impl DecisionPolicy {
+    fn from_args(args: &Args) -> Self {
+        Self {
+            disabled: hook_disabled(),
+            max_retries: args.max_retries,
+            once_per_turn: args.once_per_turn,
         }
     }
-    PathBuf::from(path)
 }
 
-// ============================================================================
-// Main Entry Point
-// ============================================================================
-
-#[tokio::main(flavor = "current_thread")]
-async fn main() {
-    let args = Args::parse();
+/// What was decided for one stop, and why
+#[derive(Debug, PartialEq)]
+enum Decision {
+    /// `CC_GOTO_WORK_DISABLE` is set (never recorded: the hook touches no files then)
+    Disabled,
+    /// Empty hook input on stdin
+    NoInput,
+    /// No transcript, or nothing in it to judge
+    NoTranscript,
+    /// A stop hook already blocked this turn and `--once-per-turn` is set
+    OncePerTurn,
+    /// `--max-retries` consecutive interventions were already made
+    MaxRetries { prior: usize, max: usize },
+    /// The model refused; pushing it to continue would only repeat the refusal
+    Refusal,
+    /// The models voted to continue
+    Block(String),
+    /// The models voted that the stop is fine
+    Allow(String),
+    /// No model returned a decision; the stop is allowed
+    Failed,
+}
 
-    if let Err(e) = run(&args).await {
-        eprintln!("Error: {}", e);
-        process::exit(1);
+impl Decision {
+    /// Value of the `decision` field in the log, database and metrics
+    fn label(&self) -> &'static str {
+        match self {
+            Decision::Disabled => "disabled",
+            Decision::NoInput => "no_input",
+            Decision::NoTranscript => "no_transcript",
+            Decision::OncePerTurn => "once_per_turn",
+            Decision::MaxRetries { .. } => "max_retries",
+            Decision::Refusal => "refusal",
+            Decision::Block(_) => "block",
+            Decision::Allow(_) => "allow",
+            Decision::Failed => "failed",
+        }
     }
 }
 
-async fn run(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
-    // Load config
-    let config_path = expand_path(&args.config);
-    let config = match Config::load(&config_path) {
-        Ok(c) => c,
-        Err(e) => {
-            eprintln!("Error: failed to load config from {:?}: {}", config_path, e);
-            eprintln!("Please create a config file at {} with the following format:", DEFAULT_CONFIG_PATH);
-            eprintln!();
-            eprintln!("providers:");
-            eprintln!("  - api_base: https://api.openai.com/v1");
-            eprintln!("    api_key: your-openai-key");
-            eprintln!("    models:");
-            eprintln!("      - gpt-4o-mini");
-            eprintln!("      - gpt-4o");
-            eprintln!("  - api_base: https://api.deepseek.com/v1");
-            eprintln!("    api_key: your-deepseek-key");
-            eprintln!("    models:");
-            eprintln!("      - deepseek-chat");
-            eprintln!();
-            eprintln!("timeout: 30  # optional");
-            eprintln!("debug: false  # optional");
-            return Err(e);
-        }
-    };
+/// Decide without the models when a policy or the transcript already settles the stop
+fn short_circuit(
+    lines: &[TranscriptLine],
+    stop_hook_active: bool,
+    policy: &DecisionPolicy,
+    logger: &DebugLogger,
+) -> Option<Decision> {
+    if policy.disabled {
+        return Some(Decision::Disabled);
+    }
 
-    // Initialize debug logger
-    let logger = DebugLogger::new(config.debug);
-    logger.log(
-        "INFO",
-        format!(
-            "cc-goto-work {} start (log={:?})",
-            env!("CARGO_PKG_VERSION"),
-            logger.path()
-        ),
-    );
-    logger.log("INFO", format!("config loaded from {:?}", config_path));
+    if lines.is_empty() {
+        logger.log("INFO", "no transcript lines; allowing stop");
+        return Some(Decision::NoTranscript);
+    }
 
-    let active_providers = &config.providers;
-    let total_models: usize = active_providers.iter().map(|p| p.models.len()).sum();
-    logger.log(
+    // Only a fresh API error may be pushed past once per turn; a repeated
+    // continuation (e.g. max_tokens again) is not re-blocked
+    if policy.once_per_turn && stop_hook_active {
+        if ends_with_error(lines) {
+            logger.log("INFO", "stop_hook_active=true but last entry is an error; running ai check");
+        } else {
+            logger.log("INFO", "stop_hook_active=true with --once-per-turn; allowing stop without ai check");
+            return Some(Decision::OncePerTurn);
+        }
+    }
+
+    let prior = count_prior_interventions(lines);
+    logger.log("DEBUG", format!("prior interventions: {}", prior));
+    if let Some(max) = policy.max_retries.filter(|max| prior >= *max) {
+        logger.log("WARN", format!("max retries reached: prior={} max={}; allowing stop", prior, max));
+        return Some(Decision::MaxRetries { prior, max });
+    }
+
+    let stop_reason = last_stop_reason(lines);
+    logger.log("DEBUG", format!("last stop_reason: {:?}", stop_reason));
+    if stop_reason == Some("refusal") {
+        logger.log("INFO", "stop_reason=refusal; allowing stop without ai check");
+        return Some(Decision::Refusal);
+    }
+
+    None
+}
+
+/// Whether the most recent parsed entry is a `type: "error"` line
+fn ends_with_error(lines: &[TranscriptLine]) -> bool {
+    lines
+        .iter()
+        .rev()
+        .find_map(|line| line.json.as_ref())
+        .is_some_and(|json| json.get("type").and_then(|v| v.as_str()) == Some("error"))
+}
+
+/// The decision path shared by the hook and the `check` subcommand
+async fn decide(
+    lines: &[TranscriptLine],
+    stop_hook_active: bool,
+    policy: &DecisionPolicy,
+    config: &Config,
+    logger: &DebugLogger,
+) -> Decision {
+    if let Some(decision) = short_circuit(lines, stop_hook_active, policy, logger) {
+        return decision;
+    }
+
+    match check_with_ai(lines, config, logger).await {
+        Some((true, reason)) => Decision::Block(reason),
+        Some((false, reason)) => Decision::Allow(reason),
+        None => Decision::Failed,
+    }
+}
+
+// ============================================================================
+// Recording
+// ============================================================================
+
+/// Save the hook input and transcript tail into `dir` as a `<run_id>-input.json`
+/// and `<run_id>-tail.jsonl` pair. Best-effort: failures are only logged.
+fn record_invocation(dir: &PathBuf, input_str: &str, lines: &[TranscriptLine], logger: &DebugLogger) {
+    let result = (|| -> io::Result<()> {
+        fs::create_dir_all(dir)?;
+
+        let input_path = dir.join(format!("{}-input.json", logger.run_id));
+        fs::write(&input_path, input_str)?;
+
+        let mut tail = String::new();
+        for line in lines {
+            tail.push_str(&line.raw);
+            tail.push('\n');
+        }
+        let tail_path = dir.join(format!("{}-tail.jsonl", logger.run_id));
+        fs::write(&tail_path, tail)?;
+
+        logger.log(
+            "INFO",
+            format!("recorded input={:?} tail={:?}", input_path, tail_path),
+        );
+        Ok(())
+    })();
+
+    if let Err(e) = result {
+        logger.log("WARN", format!("failed to record invocation into {:?}: {}", dir, e));
+    }
+}
+
+/// One line of the intervention log
+#[derive(Debug, Serialize)]
+struct InterventionRecord<'a> {
+    timestamp: u64,
+    session_id: Option<&'a str>,
+    /// "block", "allow", "failed" when no model returned a decision, or the
+    /// short-circuit that allowed the stop without the AI check (see `Decision::label`)
+    decision: &'a str,
+    reason: Option<String>,
+}
+
+impl<'a> InterventionRecord<'a> {
+    fn new(session_id: Option<&'a str>, decision: &'a Decision) -> Self {
+        Self {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            session_id,
+            decision: decision.label(),
+            reason: match decision {
+                Decision::Block(reason) | Decision::Allow(reason) => Some(reason.clone()),
+                Decision::MaxRetries { prior, max } => Some(format!("{} prior interventions, max {}", prior, max)),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// Write the decision to every sink enabled on the command line
+fn record_decision(args: &Args, session_id: Option<&str>, decision: &Decision, logger: &DebugLogger) {
+    let record = InterventionRecord::new(session_id, decision);
+    if let Some(path) = &args.log {
+        append_intervention_log(&expand_path(path), &record, logger);
+    }
+    #[cfg(feature = "sqlite")]
+    if let Some(path) = &args.decisions_db {
+        store_decision(&expand_path(path), &record, logger);
+    }
+    if let Some(path) = &args.metrics_file {
+        update_metrics_file(&expand_path(path), &record, logger);
+    }
+}
+
+/// Append the outcome of this invocation to the JSONL intervention log.
+/// Best-effort: failures are only logged.
+fn append_intervention_log(path: &PathBuf, record: &InterventionRecord, logger: &DebugLogger) {
+    let result = serde_json::to_string(record)
+        .map_err(io::Error::from)
+        .and_then(|line| {
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            file.write_all(format!("{}\n", line).as_bytes())
+        });
+
+    if let Err(e) = result {
+        logger.log("WARN", format!("failed to append intervention log {:?}: {}", path, e));
+    }
+}
+
+/// Insert the outcome of this invocation into the SQLite decisions database,
+/// creating the schema on first use. Best-effort: failures are only logged.
+#[cfg(feature = "sqlite")]
+fn store_decision(path: &PathBuf, record: &InterventionRecord, logger: &DebugLogger) {
+    let result = rusqlite::Connection::open(path).and_then(|conn| {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS decisions (
+                id INTEGER PRIMARY KEY,
+                ts INTEGER NOT NULL,
+                session_id TEXT,
+                decision TEXT NOT NULL,
+                reason TEXT
+            );
+            CREATE INDEX IF NOT EXISTS decisions_session ON decisions (session_id);
+            CREATE INDEX IF NOT EXISTS decisions_decision ON decisions (decision);
+            CREATE INDEX IF NOT EXISTS decisions_ts ON decisions (ts);",
+        )?;
+        conn.execute(
+            "INSERT INTO decisions (ts, session_id, decision, reason) VALUES (?1, ?2, ?3, ?4)",
+            rusqlite::params![record.timestamp, record.session_id, record.decision, record.reason],
+        )
+    });
+
+    if let Err(e) = result {
+        logger.log("WARN", format!("failed to store decision in {:?}: {}", path, e));
+    }
+}
+
+/// Update the Prometheus textfile at `path` with this invocation's decision.
+/// Counters accumulate across runs; the file is replaced atomically. Best-effort.
+fn update_metrics_file(path: &PathBuf, record: &InterventionRecord, logger: &DebugLogger) {
+    const DECISIONS_METRIC: &str = "cc_goto_work_decisions_total";
+    const LAST_RUN_METRIC: &str = "cc_goto_work_last_run_timestamp_seconds";
+
+    let mut counts: BTreeMap<String, u64> = BTreeMap::new();
+    if let Ok(existing) = fs::read_to_string(path) {
+        let prefix = format!("{}{{decision=\"", DECISIONS_METRIC);
+        for line in existing.lines() {
+            let Some(rest) = line.strip_prefix(&prefix) else {
+                continue;
+            };
+            if let Some((decision, value)) = rest.split_once("\"} ") {
+                if let Ok(count) = value.trim().parse::<u64>() {
+                    counts.insert(decision.to_string(), count);
+                }
+            }
+        }
+    }
+    *counts.entry(record.decision.to_string()).or_insert(0) += 1;
+
+    let mut text = String::new();
+    text.push_str(&format!("# HELP {} Stop hook invocations by decision.\n", DECISIONS_METRIC));
+    text.push_str(&format!("# TYPE {} counter\n", DECISIONS_METRIC));
+    for (decision, count) in &counts {
+        text.push_str(&format!("{}{{decision=\"{}\"}} {}\n", DECISIONS_METRIC, decision, count));
+    }
+    text.push_str(&format!("# HELP {} Unix time of the last stop hook invocation.\n", LAST_RUN_METRIC));
+    text.push_str(&format!("# TYPE {} gauge\n", LAST_RUN_METRIC));
+    text.push_str(&format!("{} {}\n", LAST_RUN_METRIC, record.timestamp));
+
+    let tmp_path = path.with_extension(format!("{}.tmp", process::id()));
+    let result = fs::write(&tmp_path, text).and_then(|_| fs::rename(&tmp_path, path));
+    if let Err(e) = result {
+        let _ = fs::remove_file(&tmp_path);
+        logger.log("WARN", format!("failed to update metrics file {:?}: {}", path, e));
+    }
+}
+
+// ============================================================================
+// Path Expansion
+// ============================================================================
+
+fn expand_path(path: &str) -> PathBuf {
+    if let Some(local) = file_uri_to_path(path) {
+        return PathBuf::from(local);
+    }
+    let path = expand_env_vars(path);
+    if path.starts_with("~/") || path.starts_with("~\\") {
+        if let Some(home) = dirs_next::home_dir() {
+            return home.join(&path[2..]);
+        }
+    }
+    PathBuf::from(path)
+}
+
+/// Expand `%VAR%` references (Windows). Unknown variables are left literal.
+#[cfg(windows)]
+fn expand_env_vars(path: &str) -> String {
+    let mut result = String::new();
+    let mut rest = path;
+
+    while let Some(start) = rest.find('%') {
+        let after = &rest[start + 1..];
+        let Some(end) = after.find('%') else {
+            break;
+        };
+        let name = &after[..end];
+        result.push_str(&rest[..start]);
+        match std::env::var(name) {
+            Ok(value) if !name.is_empty() => {
+                result.push_str(&value);
+                rest = &after[end + 1..];
+            }
+            _ => {
+                // Keep the opening `%` and retry from the closing one
+                result.push('%');
+                result.push_str(name);
+                rest = &after[end..];
+            }
+        }
+    }
+
+    result.push_str(rest);
+    result
+}
+
+/// Expand `$VAR` and `${VAR}` references (Unix). Unknown variables are left literal.
+#[cfg(not(windows))]
+fn expand_env_vars(path: &str) -> String {
+    let mut result = String::new();
+    let mut rest = path;
+
+    while let Some(start) = rest.find('$') {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+
+        let (name, consumed) = if let Some(braced) = after.strip_prefix('{') {
+            match braced.find('}') {
+                Some(end) => (&braced[..end], end + 2),
+                None => ("", 0),
+            }
+        } else {
+            let end = after
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(after.len());
+            (&after[..end], end)
+        };
+
+        match std::env::var(name) {
+            Ok(value) if !name.is_empty() => result.push_str(&value),
+            _ => result.push_str(&rest[start..start + 1 + consumed]),
+        }
+        rest = &after[consumed..];
+    }
+
+    result.push_str(rest);
+    result
+}
+
+/// Convert a `file://` URI to a local path, percent-decoding it.
+/// Handles `file:///home/...`, `file://localhost/...` and `file:///C:/...`.
+fn file_uri_to_path(uri: &str) -> Option<String> {
+    let rest = uri.strip_prefix("file://")?;
+    let rest = rest.strip_prefix("localhost").unwrap_or(rest);
+    let decoded = percent_decode(rest);
+
+    // `/C:/Users/...` -> `C:/Users/...`
+    let bytes = decoded.as_bytes();
+    if bytes.len() >= 3 && bytes[0] == b'/' && bytes[1].is_ascii_alphabetic() && bytes[2] == b':' {
+        return Some(decoded[1..].to_string());
+    }
+    Some(decoded)
+}
+
+/// Decode `%XX` escapes; malformed escapes are kept literally
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        // Check the digits explicitly: `from_str_radix` would also accept a sign (`%+1`)
+        if bytes[i] == b'%' && i + 2 < bytes.len() && bytes[i + 1..i + 3].iter().all(u8::is_ascii_hexdigit) {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+            if let Some(value) = hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                out.push(value);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Expand a transcript path, resolving relative paths against `CLAUDE_PROJECT_DIR`
+/// when set, otherwise against the hook input's `cwd`
+fn resolve_transcript_path(path: &str, cwd: Option<&str>) -> PathBuf {
+    let expanded = expand_path(path);
+    if expanded.is_absolute() {
+        return expanded;
+    }
+
+    let base = std::env::var("CLAUDE_PROJECT_DIR")
+        .ok()
+        .filter(|dir| !dir.is_empty())
+        .or_else(|| cwd.map(str::to_string));
+    match base {
+        Some(base) => expand_path(&base).join(expanded),
+        None => expanded,
+    }
+}
+
+// ============================================================================
+// Doctor
+// ============================================================================
+
+/// Run environment checks, print a checklist and return the process exit code
+fn run_doctor(args: &Args) -> i32 {
+    let mut failures = 0usize;
+    for (name, result) in doctor_checks(args) {
+        match result {
+            Ok(detail) => println!("[PASS] {}: {}", name, detail),
+            Err(detail) => {
+                failures += 1;
+                println!("[FAIL] {}: {}", name, detail);
+            }
+        }
+    }
+
+    if failures == 0 {
+        0
+    } else {
+        1
+    }
+}
+
+/// Run every doctor check and return each check's name and outcome
+fn doctor_checks(args: &Args) -> Vec<(&'static str, Result<String, String>)> {
+    let mut checks = Vec::new();
+
+    checks.push((
+        "home directory",
+        dirs_next::home_dir()
+            .map(|home| format!("{:?}", home))
+            .ok_or_else(|| "could not resolve home directory".to_string()),
+    ));
+
+    let config_path = expand_path(&args.config);
+    checks.push((
+        "config",
+        Config::load(&config_path)
+            .map(|config| {
+                let total_models: usize = config.providers.iter().map(|p| p.models.len()).sum();
+                format!(
+                    "{:?} (providers={}, models={})",
+                    config_path,
+                    config.providers.len(),
+                    total_models
+                )
+            })
+            .map_err(|e| format!("{:?}: {}", config_path, e)),
+    ));
+
+    let logger = DebugLogger::new(false);
+    checks.push(("log file", check_log_writable(logger.path())));
+
+    checks.push(("sample detection", check_sample_detection()));
+
+    checks
+}
+
+/// Check that the debug log could be written without creating it: an existing
+/// file is opened for append, otherwise a probe file is created and removed
+fn check_log_writable(path: &Path) -> Result<String, String> {
+    if path.exists() {
+        return OpenOptions::new()
+            .append(true)
+            .open(path)
+            .map(|_| format!("{:?} is writable", path))
+            .map_err(|e| format!("{:?}: {}", path, e));
+    }
+
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let probe = dir.join(format!(".cc-goto-work-doctor-{}", process::id()));
+    File::create(&probe)
+        .and_then(|_| fs::remove_file(&probe))
+        .map(|_| format!("{:?} can be created", path))
+        .map_err(|e| format!("{:?} is not writable: {}", dir, e))
+}
+
+/// Run the self-test samples and summarize them for the doctor checklist
+fn check_sample_detection() -> Result<String, String> {
+    let results = run_selftest_checks();
+    let failed: Vec<&str> = results
+        .iter()
+        .filter(|(_, result)| result.is_err())
+        .map(|(name, _)| name.as_str())
+        .collect();
+    if failed.is_empty() {
+        Ok(format!("{} self-test samples passed", results.len()))
+    } else {
+        Err(format!("self-test samples failed: {} (run `cc-goto-work selftest`)", failed.join(", ")))
+    }
+}
+
+// ============================================================================
+// Self-Test
+// ============================================================================
+
+const SAMPLE_USER: &str = r#"{"type":"user","message":{"content":"Fix the failing test"}}"#;
+const SAMPLE_MAX_TOKENS: &str =
+    r#"{"type":"assistant","message":{"content":[{"type":"text","text":"I will now run"}],"stop_reason":"max_tokens"}}"#;
+const SAMPLE_REFUSAL: &str =
+    r#"{"type":"assistant","message":{"content":[{"type":"text","text":"I can't help with that."}],"stop_reason":"refusal"}}"#;
+const SAMPLE_INTERVENTION: &str =
+    r#"{"type":"user","message":{"content":"Stop hook feedback:\nAI: vote: 2/3 continue, 1/3 stop; unfinished"}}"#;
+const SAMPLE_ERROR: &str = r#"{"type":"error","error":{"type":"rate_limit_error","status":429,"message":"Rate limited"}}"#;
+
+/// A sample transcript run through the hook's decision path
+struct SelftestCase {
+    name: &'static str,
+    lines: &'static [&'static str],
+    stop_hook_active: bool,
+    policy: DecisionPolicy,
+    /// Expected short-circuit decision; None means the AI check must be consulted
+    expected: Option<Decision>,
+    /// Text the formatted transcript sent to the models must contain
+    formatted: &'static str,
+}
+
+fn selftest_cases() -> Vec<SelftestCase> {
+    let default_policy = || DecisionPolicy {
+        disabled: false,
+        max_retries: None,
+        once_per_turn: false,
+    };
+    vec![
+        SelftestCase {
+            name: "max_tokens goes to the AI check",
+            lines: &[SAMPLE_USER, SAMPLE_MAX_TOKENS],
+            stop_hook_active: false,
+            policy: default_policy(),
+            expected: None,
+            formatted: "[stop_reason: max_tokens]",
+        },
+        SelftestCase {
+            name: "refusal allows the stop",
+            lines: &[SAMPLE_USER, SAMPLE_REFUSAL],
+            stop_hook_active: false,
+            policy: default_policy(),
+            expected: Some(Decision::Refusal),
+            formatted: "[stop_reason: refusal]",
+        },
+        SelftestCase {
+            name: "--max-retries gives up after repeated interventions",
+            lines: &[SAMPLE_USER, SAMPLE_MAX_TOKENS, SAMPLE_INTERVENTION, SAMPLE_MAX_TOKENS, SAMPLE_INTERVENTION, SAMPLE_MAX_TOKENS],
+            stop_hook_active: false,
+            policy: DecisionPolicy {
+                disabled: false,
+                max_retries: Some(2),
+                once_per_turn: false,
+            },
+            expected: Some(Decision::MaxRetries { prior: 2, max: 2 }),
+            formatted: "[stop_reason: max_tokens]",
+        },
+        SelftestCase {
+            name: "--once-per-turn allows a repeated continuation",
+            lines: &[SAMPLE_USER, SAMPLE_MAX_TOKENS],
+            stop_hook_active: true,
+            policy: DecisionPolicy {
+                disabled: false,
+                max_retries: None,
+                once_per_turn: true,
+            },
+            expected: Some(Decision::OncePerTurn),
+            formatted: "[stop_reason: max_tokens]",
+        },
+        SelftestCase {
+            name: "--once-per-turn still checks a fresh error",
+            lines: &[SAMPLE_USER, SAMPLE_MAX_TOKENS, SAMPLE_ERROR],
+            stop_hook_active: true,
+            policy: DecisionPolicy {
+                disabled: false,
+                max_retries: None,
+                once_per_turn: true,
+            },
+            expected: None,
+            formatted: "[Error: ",
+        },
+    ]
+}
+
+/// Sample model replies: (name, reply, expected should_continue; None = unparseable)
+const SELFTEST_RESPONSES: [(&str, &str, Option<bool>); 4] = [
+    ("plain json reply", r#"{"should_continue": true, "reason": "cut off"}"#, Some(true)),
+    (
+        "reply with thinking tags",
+        r#"<think>{"should_continue": true}</think>{"should_continue": false, "reason": "done"}"#,
+        Some(false),
+    ),
+    (
+        "reply with surrounding text",
+        r#"Sure. {"should_continue": true, "reason": "unfinished"} Hope this helps."#,
+        Some(true),
+    ),
+    ("reply without json", "I am not sure.", None),
+];
+
+/// Run every embedded sample and return each check's name and outcome
+fn run_selftest_checks() -> Vec<(String, Result<(), String>)> {
+    let mut results = Vec::new();
+
+    let logger = DebugLogger::new(false);
+    for case in selftest_cases() {
+        let lines = parse_transcript_lines(case.lines.join("\n").as_bytes(), false);
+        let actual = short_circuit(&lines, case.stop_hook_active, &case.policy, &logger);
+        let result = if lines.len() != case.lines.len() || lines.iter().any(|l| l.json.is_none()) {
+            Err("sample line failed to parse as JSON".to_string())
+        } else if actual != case.expected {
+            Err(format!("expected {:?}, got {:?}", case.expected, actual))
+        } else if !format_transcript_for_ai(&lines, AI_MAX_LINES).contains(case.formatted) {
+            Err(format!("transcript sent to the models lacks {:?}", case.formatted))
+        } else {
+            Ok(())
+        };
+        results.push((format!("decision: {}", case.name), result));
+    }
+
+    for (name, reply, expected) in SELFTEST_RESPONSES {
+        let actual = parse_ai_response(reply).map(|r| r.should_continue);
+        let result = if actual == expected {
+            Ok(())
+        } else {
+            Err(format!("expected should_continue {:?}, got {:?}", expected, actual))
+        };
+        results.push((format!("response: {}", name), result));
+    }
+
+    results
+}
+
+/// Print PASS/FAIL for each self-test sample and return the process exit code
+fn run_selftest() -> i32 {
+    let results = run_selftest_checks();
+    let mut failures = 0usize;
+    for (name, result) in &results {
+        match result {
+            Ok(()) => println!("[PASS] {}", name),
+            Err(e) => {
+                failures += 1;
+                println!("[FAIL] {}: {}", name, e);
+            }
+        }
+    }
+    println!("{}/{} passed", results.len() - failures, results.len());
+
+    if failures == 0 {
+        0
+    } else {
+        1
+    }
+}
+
+// ============================================================================
+// Dump Lines
+// ============================================================================
+
+/// Print each transcript tail line with its parse status and return the process exit code
+fn run_dump_lines(file: &str, tail_bytes: u64, encoding: TranscriptEncoding) -> i32 {
+    let path = expand_path(file);
+    // The hook treats an unopenable transcript as empty; a debugging aid should say so
+    if let Err(e) = File::open(&path) {
+        diag!("Error: cannot open {:?}: {}", path, e);
+        return 1;
+    }
+    let lines = match read_transcript_tail(&path, tail_bytes, encoding) {
+        Ok(lines) => lines,
+        Err(e) => {
+            diag!("Error: failed to read {:?}: {}", path, e);
+            return 1;
+        }
+    };
+
+    for row in dump_rows(&lines) {
+        println!("{}", row);
+    }
+    println!("{} lines ({} parsed as JSON)", lines.len(), lines.iter().filter(|l| l.json.is_some()).count());
+
+    0
+}
+
+/// One row per transcript line with its index, parse status, type and stop_reason
+fn dump_rows(lines: &[TranscriptLine]) -> Vec<String> {
+    lines
+        .iter()
+        .enumerate()
+        .map(|(index, line)| match &line.json {
+            Some(json) => {
+                let entry_type = json.get("type").and_then(|v| v.as_str()).unwrap_or("-");
+                let stop_reason = json
+                    .pointer("/message/stop_reason")
+                    .and_then(|v| v.as_str())
+                    .unwrap_or("-");
+                format!(
+                    "{:>4} json=ok  type={} stop_reason={} | {}",
+                    index,
+                    entry_type,
+                    stop_reason,
+                    truncate_for_log(&line.raw, 120)
+                )
+            }
+            None => format!("{:>4} json=err | {}", index, truncate_for_log(&line.raw, 120)),
+        })
+        .collect()
+}
+
+// ============================================================================
+// Check
+// ============================================================================
+
+/// Run the AI check on a transcript file and print the decision as text
+async fn run_check(args: &Args, file: &str, stop_hook_active: bool) -> Result<(), HookError> {
+    let config = load_config(&expand_path(&args.config))?;
+    let logger = DebugLogger::new(config.debug).with_stderr(args.debug_enabled());
+
+    let path = expand_path(file);
+    let lines = load_transcript(
+        &path,
+        effective_tail_bytes(args.tail_bytes, config.tail_bytes),
+        args.transcript_encoding,
+    )?;
+    println!("transcript: {:?} ({} lines)", path, lines.len());
+
+    let policy = DecisionPolicy::from_args(args);
+    match decide(&lines, stop_hook_active, &policy, &config, &logger).await {
+        Decision::Disabled => println!("decision: allow ({} is set)", DISABLE_ENV_VAR),
+        Decision::NoInput | Decision::NoTranscript => println!("decision: allow (no transcript lines)"),
+        Decision::OncePerTurn => println!("decision: allow (stop hook already active, --once-per-turn)"),
+        Decision::MaxRetries { prior, .. } => {
+            println!("decision: allow (max retries reached after {} interventions)", prior)
+        }
+        Decision::Refusal => println!("decision: allow (stop_reason: refusal)"),
+        Decision::Block(reason) => {
+            println!("decision: block");
+            println!("reason: {}", reason);
+            println!("hook output: {}", block_output(&reason, &config, args).to_json(args.validate_output, args.terse)?);
+        }
+        Decision::Allow(reason) => {
+            println!("decision: allow");
+            println!("reason: {}", reason);
+        }
+        Decision::Failed => println!("decision: allow (AI check failed)"),
+    }
+
+    Ok(())
+}
+
+// ============================================================================
+// Main Entry Point
+// ============================================================================
+
+/// Result of a hook invocation
+enum Outcome {
+    /// Stop allowed (no output)
+    Allowed,
+    /// Stop blocked (block decision printed)
+    Blocked,
+}
+
+/// Failures that abort a hook invocation. Exit code 2 is avoided because
+/// Claude Code treats it as a blocking error and feeds stderr to the model.
+#[derive(Debug)]
+enum HookError {
+    /// Config file missing, unreadable or invalid
+    Config {
+        path: PathBuf,
+        source: Box<dyn std::error::Error>,
+    },
+    /// Stdin could not be read (including the input timeout)
+    StdinRead(io::Error),
+    /// Stdin was not a hook input JSON object
+    InputParse(String),
+    /// Transcript exists but could not be read
+    TranscriptRead {
+        path: PathBuf,
+        source: Box<dyn std::error::Error>,
+    },
+    /// Hook output could not be serialized or failed validation
+    Serialize(Box<dyn std::error::Error>),
+}
+
+impl HookError {
+    fn exit_code(&self) -> i32 {
+        match self {
+            HookError::Config { .. } => 4,
+            HookError::StdinRead(_) => 5,
+            HookError::InputParse(_) => 6,
+            HookError::TranscriptRead { .. } => 7,
+            HookError::Serialize(_) => 8,
+        }
+    }
+}
+
+impl std::fmt::Display for HookError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HookError::Config { path, source } => write!(f, "failed to load config from {:?}: {}", path, source),
+            HookError::StdinRead(e) => write!(f, "failed to read hook input from stdin: {}", e),
+            HookError::InputParse(e) => write!(f, "{}", e),
+            HookError::TranscriptRead { path, source } => {
+                write!(f, "failed to read transcript {:?}: {}", path, source)
+            }
+            HookError::Serialize(e) => write!(f, "failed to serialize hook output: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for HookError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            HookError::Config { source, .. } | HookError::TranscriptRead { source, .. } => Some(source.as_ref()),
+            HookError::StdinRead(e) => Some(e),
+            HookError::InputParse(_) => None,
+            HookError::Serialize(e) => Some(e.as_ref()),
+        }
+    }
+}
+
+/// Load the config file for a hook or `check` run
+fn load_config(path: &Path) -> Result<Config, HookError> {
+    Config::load(&path.to_path_buf()).map_err(|source| HookError::Config {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+/// Read the transcript tail for a hook or `check` run
+fn load_transcript(
+    path: &Path,
+    tail_bytes: u64,
+    encoding: TranscriptEncoding,
+) -> Result<Vec<TranscriptLine>, HookError> {
+    read_transcript_tail(path, tail_bytes, encoding).map_err(|source| HookError::TranscriptRead {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+/// Print the expected config format after a config load failure
+fn print_config_help() {
+    diag!("Please create a config file at {} with the following format:", DEFAULT_CONFIG_PATH);
+    diag!();
+    diag!("providers:");
+    diag!("  - api_base: https://api.openai.com/v1");
+    diag!("    api_key: your-openai-key");
+    diag!("    models:");
+    diag!("      - gpt-4o-mini");
+    diag!("      - gpt-4o");
+    diag!("  - api_base: https://api.deepseek.com/v1");
+    diag!("    api_key: your-deepseek-key");
+    diag!("    models:");
+    diag!("      - deepseek-chat");
+    diag!();
+    diag!("timeout: 30  # optional");
+    diag!("debug: false  # optional");
+    diag!("tail_bytes: 10240  # optional");
+    diag!("max_lines: 20  # optional");
+    diag!("reason_template: \"AI: {{reason}}\"  # optional");
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let args = Args::parse();
+    QUIET.store(args.quiet, Ordering::Relaxed);
+
+    match &args.command {
+        Some(Command::Doctor) => process::exit(run_doctor(&args)),
+        Some(Command::Selftest) => process::exit(run_selftest()),
+        Some(Command::DumpLines { file }) => {
+            // Use the hook's configured window; a broken config shouldn't stop a dump
+            let configured = load_config(&expand_path(&args.config)).map_or(TAIL_READ_BYTES, |c| c.tail_bytes);
+            process::exit(run_dump_lines(
+                file,
+                effective_tail_bytes(args.tail_bytes, configured),
+                args.transcript_encoding,
+            ))
+        }
+        Some(Command::Check { file, stop_hook_active }) => {
+            if let Err(e) = run_check(&args, file, *stop_hook_active).await {
+                exit_with_error(e);
+            }
+            return;
+        }
+        None => {}
+    }
+
+    match run(&args).await {
+        Ok(Outcome::Blocked) if args.exit_code => process::exit(EXIT_BLOCKED),
+        Ok(_) => {}
+        Err(e) => exit_with_error(e),
+    }
+}
+
+/// Report a failed run and exit with the error's code
+fn exit_with_error(e: HookError) -> ! {
+    diag!("Error: {}", e);
+    if matches!(e, HookError::Config { .. }) {
+        print_config_help();
+    }
+    process::exit(e.exit_code())
+}
+
+/// Whether the kill switch env var is set to a truthy value
+fn hook_disabled() -> bool {
+    env_flag(DISABLE_ENV_VAR)
+}
+
+async fn run(args: &Args) -> Result<Outcome, HookError> {
+    // Kill switch: allow the stop before touching config, stdin or the transcript
+    if hook_disabled() {
+        return Ok(Outcome::Allowed);
+    }
+
+    // Load config
+    let config_path = expand_path(&args.config);
+    let config = load_config(&config_path)?;
+
+    // Initialize debug logger
+    let logger = DebugLogger::new(config.debug).with_stderr(args.debug_enabled());
+    logger.log(
+        "INFO",
+        format!(
+            "cc-goto-work {} start (log={:?})",
+            env!("CARGO_PKG_VERSION"),
+            logger.path()
+        ),
+    );
+    logger.log("INFO", format!("config loaded from {:?}", config_path));
+
+    let active_providers = &config.providers;
+    let total_models: usize = active_providers.iter().map(|p| p.models.len()).sum();
+    logger.log(
         "DEBUG",
         format!(
-            "config: providers={}, models={}, timeout={}s, system_prompt_custom={}",
+            "config: providers={}, models={}, timeout={}s, system_prompt_custom={}, tail_bytes={}, max_lines={}",
             active_providers.len(),
             total_models,
             config.timeout,
-            config.system_prompt.is_some()
+            config.system_prompt.is_some(),
+            config.tail_bytes,
+            config.max_lines
         ),
     );
 
     // Read input from stdin
-    let mut input_str = String::new();
-    io::stdin().read_to_string(&mut input_str)?;
+    let input_str = read_input(io::stdin(), args.input_timeout)?;
     logger.log("DEBUG", format!("stdin bytes: {}", input_str.len()));
 
-    let input: HookInput = match serde_json::from_str(&input_str) {
+    if input_str.trim().is_empty() {
+        logger.log("INFO", "empty stdin; allowing stop");
+        record_decision(args, None, &Decision::NoInput, &logger);
+        return Ok(Outcome::Allowed);
+    }
+
+    let input = match parse_hook_input(&input_str) {
         Ok(v) => v,
         Err(e) => {
             logger.log("ERROR", format!("failed to parse stdin JSON: {}", e));
-            return Err(e.into());
+            return Err(e);
         }
     };
     logger.log(
@@ -912,10 +2584,11 @@ async fn run(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
 
     // Get transcript path
     let transcript_path = match &input.transcript_path {
-        Some(path) => expand_path(path),
+        Some(path) => resolve_transcript_path(path, input.cwd.as_deref()),
         None => {
             logger.log("INFO", "no transcript_path in stdin; allowing stop");
-            return Ok(());
+            record_decision(args, input.session_id.as_deref(), &Decision::NoTranscript, &logger);
+            return Ok(Outcome::Allowed);
         }
     };
     logger.log(
@@ -928,40 +2601,775 @@ async fn run(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
     );
 
     // Read transcript tail
-    let lines = read_transcript_tail(&transcript_path)?;
-    logger.log("INFO", format!("transcript lines read: {}", lines.len()));
-    if lines.is_empty() {
-        logger.log("INFO", "no transcript lines; allowing stop");
-        return Ok(());
+    let lines = load_transcript(
+        &transcript_path,
+        effective_tail_bytes(args.tail_bytes, config.tail_bytes),
+        args.transcript_encoding,
+    )?;
+    logger.log(
+        "INFO",
+        format!(
+            "transcript lines read: {} (json={})",
+            lines.len(),
+            lines.iter().filter(|l| l.json.is_some()).count()
+        ),
+    );
+
+    if let Some(dir) = &args.record {
+        record_invocation(&expand_path(dir), &input_str, &lines, &logger);
     }
 
-    // Check with AI
-    match check_with_ai(&lines, &config, &logger).await {
-        Some((true, reason)) => {
+    let policy = DecisionPolicy::from_args(args);
+    let decision = decide(&lines, input.stop_hook_active == Some(true), &policy, &config, &logger).await;
+
+    record_decision(args, input.session_id.as_deref(), &decision, &logger);
+
+    match decision {
+        Decision::MaxRetries { prior, max } => {
+            diag!(
+                "Warning: already blocked {} times in a row (--max-retries {}), allowing stop",
+                prior, max
+            );
+        }
+        Decision::Block(reason) => {
             // AI says continue
             logger.log(
                 "INFO",
                 format!("hook output: decision=block reason={}", truncate_for_log(&reason, 300)),
             );
-            let output = HookOutput {
-                decision: "block".to_string(),
-                reason: format!("AI: {}", reason),
-            };
-            println!("{}", serde_json::to_string(&output)?);
+            let output = block_output(&reason, &config, args);
+            println!("{}", output.to_json(args.validate_output, args.terse)?);
+            return Ok(Outcome::Blocked);
         }
-        Some((false, reason)) => {
+        Decision::Allow(reason) => {
             // AI says stop is fine - do nothing
             logger.log(
                 "INFO",
                 format!("ai decision: allow stop, reason={}", truncate_for_log(&reason, 300)),
             );
         }
-        None => {
+        Decision::Failed => {
             // AI check failed - allow stop by default
-            eprintln!("Warning: AI check failed, allowing stop");
+            diag!("Warning: AI check failed, allowing stop");
             logger.log("WARN", "ai check failed; allowing stop by default");
         }
+        Decision::Disabled | Decision::NoInput | Decision::NoTranscript | Decision::OncePerTurn | Decision::Refusal => {}
     }
 
-    Ok(())
+    Ok(Outcome::Allowed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fresh scratch directory under the system temp dir
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cc-goto-work-test-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    const USER_LINE: &str = r#"{"type":"user","message":{"content":"Do X"}}"#;
+    const MAX_TOKENS_LINE: &str =
+        r#"{"type":"assistant","message":{"content":[{"type":"text","text":"partial"}],"stop_reason":"max_tokens"}}"#;
+    const STREAMING_LINE: &str = r#"{"type":"assistant","message":{"content":[{"type":"text","text":"more"}]}}"#;
+
+    #[test]
+    fn rotated_segments_are_merged_oldest_first() {
+        let dir = scratch_dir("rotation");
+        fs::write(dir.join("transcript.1.jsonl"), format!("{}\n{}\n", USER_LINE, MAX_TOKENS_LINE)).unwrap();
+        fs::write(dir.join("transcript.jsonl"), format!("{}\n", STREAMING_LINE)).unwrap();
+
+        let lines = read_transcript_tail(&dir.join("transcript.jsonl"), TAIL_READ_BYTES, TranscriptEncoding::Utf8).unwrap();
+        let raws: Vec<&str> = lines.iter().map(|l| l.raw.as_str()).collect();
+        assert_eq!(raws, [USER_LINE, MAX_TOKENS_LINE, STREAMING_LINE]);
+        assert_eq!(last_stop_reason(&lines), Some("max_tokens"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn rotated_segments_are_not_merged_across_a_gap() {
+        let dir = scratch_dir("rotation-gap");
+        fs::write(dir.join("transcript.1.jsonl"), format!("{}\n", SAMPLE_REFUSAL)).unwrap();
+        let newest: String = (0..4000).map(|_| format!("{}\n", STREAMING_LINE)).collect();
+        assert!(newest.len() as u64 > MAX_TAIL_READ_BYTES);
+        fs::write(dir.join("transcript.jsonl"), newest).unwrap();
+
+        let lines = read_transcript_tail(&dir.join("transcript.jsonl"), TAIL_READ_BYTES, TranscriptEncoding::Utf8).unwrap();
+        assert_eq!(last_stop_reason(&lines), None);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn rotated_segment_path_splits_on_the_real_extension() {
+        let n1 = |p: &str| rotated_segment_path(Path::new(p), 1).unwrap();
+        assert_eq!(n1("/t/transcript.jsonl"), PathBuf::from("/t/transcript.1.jsonl"));
+        assert_eq!(n1("/t/a.b.jsonl"), PathBuf::from("/t/a.b.1.jsonl"));
+        assert_eq!(n1("/t/transcript"), PathBuf::from("/t/transcript.1"));
+    }
+
+    /// Reader that fails on the first read
+    struct FailingReader;
+
+    impl Read for FailingReader {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::BrokenPipe, "closed"))
+        }
+    }
+
+    /// Reader that never produces data within a test's patience
+    struct StalledReader;
+
+    impl Read for StalledReader {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            std::thread::sleep(Duration::from_secs(60));
+            Ok(0)
+        }
+    }
+
+    #[test]
+    fn missing_config_is_a_config_error() {
+        let err = load_config(Path::new("/nonexistent/cc-goto-work/config.yaml")).unwrap_err();
+        assert!(matches!(err, HookError::Config { .. }), "{:?}", err);
+        assert_eq!(err.exit_code(), 4);
+    }
+
+    #[test]
+    fn unreadable_stdin_is_a_stdin_error() {
+        let err = read_input(FailingReader, 0).unwrap_err();
+        assert!(matches!(err, HookError::StdinRead(_)), "{:?}", err);
+        let err = read_input(StalledReader, 1).unwrap_err();
+        assert!(matches!(&err, HookError::StdinRead(e) if e.kind() == io::ErrorKind::TimedOut), "{:?}", err);
+        assert_eq!(err.exit_code(), 5);
+    }
+
+    #[test]
+    fn bad_hook_input_is_an_input_parse_error() {
+        for input in ["not json", "[1, 2]", r#"{"stop_hook_active": "yes"}"#] {
+            let err = parse_hook_input(input).unwrap_err();
+            assert!(matches!(err, HookError::InputParse(_)), "{:?}: {:?}", input, err);
+            assert_eq!(err.exit_code(), 6);
+        }
+        assert!(parse_hook_input(r#"{"transcript_path": "/t.jsonl"}"#).is_ok());
+    }
+
+    #[test]
+    fn corrupt_transcript_is_a_transcript_error() {
+        let dir = scratch_dir("corrupt-gz");
+        let path = dir.join("transcript.jsonl.gz");
+        fs::write(&path, [0x1f, 0x8b, 0x00, 0x00, 0xff, 0xff]).unwrap();
+
+        let err = load_transcript(&path, TAIL_READ_BYTES, TranscriptEncoding::Utf16le).unwrap_err();
+        assert!(matches!(err, HookError::TranscriptRead { .. }), "{:?}", err);
+        assert_eq!(err.exit_code(), 7);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn invalid_hook_output_is_a_serialize_error() {
+        let output = HookOutput {
+            decision: "block".to_string(),
+            reason: String::new(),
+            continue_session: None,
+            stop_reason: None,
+            suppress_output: None,
+        };
+        let err = output.to_json(true, false).unwrap_err();
+        assert!(matches!(err, HookError::Serialize(_)), "{:?}", err);
+        assert_eq!(err.exit_code(), 8);
+    }
+
+    #[test]
+    fn reason_template_must_keep_the_vote_summary() {
+        let dir = scratch_dir("reason-template");
+        let path = dir.join("config.yaml");
+        let base = "providers:\n  - api_base: http://localhost\n    api_key: k\n    models: [m]\n";
+
+        fs::write(&path, format!("{}reason_template: \"Keep going\"\n", base)).unwrap();
+        assert!(Config::load(&path).is_err());
+        fs::write(&path, format!("{}reason_template: \"Keep going ({{reason}})\"\n", base)).unwrap();
+        assert!(Config::load(&path).is_ok());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn non_finite_numbers_become_null_outside_strings() {
+        assert_eq!(
+            replace_non_finite_numbers(r#"{"a":NaN,"b":[Infinity,-Infinity]}"#).as_deref(),
+            Some(r#"{"a":null,"b":[null,null]}"#)
+        );
+        assert_eq!(
+            replace_non_finite_numbers(r#"{"text":"NaN \"Infinity\"","n":NaN}"#).as_deref(),
+            Some(r#"{"text":"NaN \"Infinity\"","n":null}"#)
+        );
+        assert_eq!(replace_non_finite_numbers(r#"{"text":"NaN and -Infinity"}"#), None);
+        assert_eq!(replace_non_finite_numbers(r#"{"n":1.5}"#), None);
+    }
+
+    #[test]
+    fn transcript_json_tolerates_non_finite_numbers() {
+        let json = parse_transcript_json(r#"{"usage":{"ratio":NaN},"note":"NaN"}"#).unwrap();
+        assert!(json["usage"]["ratio"].is_null());
+        assert_eq!(json["note"], "NaN");
+        assert!(parse_transcript_json("{not json").is_none());
+    }
+
+    #[test]
+    fn brace_depth_ignores_braces_in_strings() {
+        assert_eq!(brace_depth("{"), 1);
+        assert_eq!(brace_depth(r#"{"a": {"b": 1}"#), 1);
+        assert_eq!(brace_depth(r#"{"text": "}}}"}"#), 0);
+        assert_eq!(brace_depth(r#"{"text": "a \" } b", "c": {"#), 2);
+        assert_eq!(brace_depth(r#"{"path": "C:\\"}"#), 0);
+    }
+
+    #[test]
+    fn pretty_printed_entries_are_reassembled() {
+        let input = format!(
+            "{}\n{{\n  \"type\": \"assistant\",\n  \"message\": {{\"text\": \"{{ not a brace\", \"stop_reason\": \"max_tokens\"}}\n}}\n",
+            USER_LINE
+        );
+        let lines = parse_transcript_lines(input.as_bytes(), false);
+        assert_eq!(lines.len(), 2);
+        assert!(lines.iter().all(|l| l.json.is_some()));
+        assert_eq!(last_stop_reason(&lines), Some("max_tokens"));
+    }
+
+    #[test]
+    fn percent_decode_keeps_malformed_escapes() {
+        assert_eq!(percent_decode("a%20b%2Fc"), "a b/c");
+        assert_eq!(percent_decode("%E4%BD%A0"), "你");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%4"), "%4");
+        assert_eq!(percent_decode("%zz%41"), "%zzA");
+        assert_eq!(percent_decode("%+1"), "%+1");
+    }
+
+    #[test]
+    fn file_uris_become_local_paths() {
+        assert_eq!(file_uri_to_path("file:///home/u/a%20b.jsonl").as_deref(), Some("/home/u/a b.jsonl"));
+        assert_eq!(file_uri_to_path("file://localhost/tmp/t.jsonl").as_deref(), Some("/tmp/t.jsonl"));
+        assert_eq!(file_uri_to_path("file:///C:/Users/u/t.jsonl").as_deref(), Some("C:/Users/u/t.jsonl"));
+        assert_eq!(file_uri_to_path("file:///c%3A/t.jsonl").as_deref(), Some("c:/t.jsonl"));
+        assert_eq!(file_uri_to_path("/tmp/t.jsonl"), None);
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn env_vars_expand_with_unknown_and_malformed_references_kept() {
+        std::env::set_var("CC_GOTO_WORK_TEST_DIR", "/data");
+        assert_eq!(expand_env_vars("$CC_GOTO_WORK_TEST_DIR/t.jsonl"), "/data/t.jsonl");
+        assert_eq!(expand_env_vars("${CC_GOTO_WORK_TEST_DIR}x/t.jsonl"), "/datax/t.jsonl");
+        assert_eq!(expand_env_vars("$CC_GOTO_WORK_TEST_UNSET/t"), "$CC_GOTO_WORK_TEST_UNSET/t");
+        assert_eq!(expand_env_vars("${CC_GOTO_WORK_TEST_UNSET}/t"), "${CC_GOTO_WORK_TEST_UNSET}/t");
+        assert_eq!(expand_env_vars("${CC_GOTO_WORK_TEST_DIR"), "${CC_GOTO_WORK_TEST_DIR");
+        assert_eq!(expand_env_vars("a/${}/b"), "a/${}/b");
+        assert_eq!(expand_env_vars("cost$"), "cost$");
+        assert_eq!(expand_env_vars("$$/$"), "$$/$");
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn env_vars_expand_with_unknown_and_malformed_references_kept() {
+        std::env::set_var("CC_GOTO_WORK_TEST_DIR", "D:\\data");
+        assert_eq!(expand_env_vars("%CC_GOTO_WORK_TEST_DIR%\\t.jsonl"), "D:\\data\\t.jsonl");
+        assert_eq!(expand_env_vars("%CC_GOTO_WORK_TEST_UNSET%\\t"), "%CC_GOTO_WORK_TEST_UNSET%\\t");
+        assert_eq!(expand_env_vars("100%"), "100%");
+        assert_eq!(expand_env_vars("%%"), "%%");
+    }
+
+    #[test]
+    fn byte_sizes_accept_suffixes_and_reject_garbage() {
+        assert_eq!(parse_byte_size("10240"), Ok(10240));
+        assert_eq!(parse_byte_size("512b"), Ok(512));
+        assert_eq!(parse_byte_size("64k"), Ok(64 * 1024));
+        assert_eq!(parse_byte_size(" 64 KB "), Ok(64 * 1024));
+        assert_eq!(parse_byte_size("1M"), Ok(1024 * 1024));
+        for value in ["", "k", "-1", "1.5k", "10g", "99999999999999999999", "18014398509481984m"] {
+            assert!(parse_byte_size(value).is_err(), "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn tail_bytes_prefers_cli_and_clamps_to_minimum() {
+        assert_eq!(effective_tail_bytes(None, 20_000), 20_000);
+        assert_eq!(effective_tail_bytes(Some(64 * 1024), 20_000), 64 * 1024);
+        assert_eq!(effective_tail_bytes(Some(10), 20_000), MIN_TAIL_READ_BYTES);
+    }
+
+
+    fn sample_lines(raw: &[&str]) -> Vec<TranscriptLine> {
+        parse_transcript_lines(raw.join("\n").as_bytes(), false)
+    }
+
+    fn policy(max_retries: Option<usize>, once_per_turn: bool) -> DecisionPolicy {
+        DecisionPolicy {
+            disabled: false,
+            max_retries,
+            once_per_turn,
+        }
+    }
+
+    #[test]
+    fn short_circuit_matches_the_hook_policies() {
+        let logger = DebugLogger::new(false);
+
+        assert_eq!(short_circuit(&[], false, &policy(None, false), &logger), Some(Decision::NoTranscript));
+        assert_eq!(
+            short_circuit(&sample_lines(&[USER_LINE, SAMPLE_REFUSAL]), false, &policy(None, false), &logger),
+            Some(Decision::Refusal)
+        );
+        let retried = sample_lines(&[USER_LINE, MAX_TOKENS_LINE, SAMPLE_INTERVENTION, MAX_TOKENS_LINE, SAMPLE_INTERVENTION, MAX_TOKENS_LINE]);
+        assert_eq!(
+            short_circuit(&retried, false, &policy(Some(2), false), &logger),
+            Some(Decision::MaxRetries { prior: 2, max: 2 })
+        );
+        assert_eq!(short_circuit(&retried, false, &policy(Some(3), false), &logger), None);
+        assert_eq!(short_circuit(&retried, false, &policy(None, false), &logger), None);
+    }
+
+    #[test]
+    fn once_per_turn_allows_a_repeated_continuation() {
+        let logger = DebugLogger::new(false);
+        let lines = sample_lines(&[USER_LINE, MAX_TOKENS_LINE]);
+
+        assert_eq!(short_circuit(&lines, true, &policy(None, true), &logger), Some(Decision::OncePerTurn));
+        assert_eq!(short_circuit(&lines, false, &policy(None, true), &logger), None);
+        assert_eq!(short_circuit(&lines, true, &policy(None, false), &logger), None);
+    }
+
+    #[test]
+    fn once_per_turn_still_checks_a_fresh_error() {
+        let logger = DebugLogger::new(false);
+        let lines = sample_lines(&[USER_LINE, MAX_TOKENS_LINE, SAMPLE_ERROR]);
+
+        assert_eq!(short_circuit(&lines, true, &policy(None, true), &logger), None);
+    }
+
+    #[test]
+    fn every_decision_is_recorded_under_its_own_label() {
+        let decisions = [
+            Decision::Disabled,
+            Decision::NoInput,
+            Decision::NoTranscript,
+            Decision::OncePerTurn,
+            Decision::MaxRetries { prior: 3, max: 3 },
+            Decision::Refusal,
+            Decision::Block("b".to_string()),
+            Decision::Allow("a".to_string()),
+            Decision::Failed,
+        ];
+        let labels: std::collections::HashSet<&str> = decisions.iter().map(Decision::label).collect();
+        assert_eq!(labels.len(), decisions.len());
+
+        let record = InterventionRecord::new(Some("s"), &decisions[4]);
+        assert_eq!(record.decision, "max_retries");
+        assert_eq!(record.reason.as_deref(), Some("3 prior interventions, max 3"));
+    }
+
+    #[test]
+    fn terse_keys_round_trip() {
+        let output = HookOutput {
+            decision: "block".to_string(),
+            reason: "keep going".to_string(),
+            continue_session: Some(true),
+            stop_reason: Some("why".to_string()),
+            suppress_output: Some(true),
+        };
+        let full = serde_json::to_value(&output).unwrap();
+        let terse: serde_json::Value = serde_json::from_str(&output.to_json(true, true).unwrap()).unwrap();
+
+        assert_eq!(terse, serde_json::json!({"d": "block", "r": "keep going", "c": true, "s": "why", "q": true}));
+        assert_eq!(decode_terse(terse), full);
+    }
+
+    #[test]
+    fn hook_output_validation() {
+        let valid = [
+            serde_json::json!({"decision": "block", "reason": "go on"}),
+            serde_json::json!({"decision": "block", "reason": "go on", "suppressOutput": true}),
+            serde_json::json!({"decision": "block", "reason": "go on", "continue": false, "stopReason": "done"}),
+        ];
+        for value in &valid {
+            assert_eq!(validate_hook_output(value), Ok(()), "{}", value);
+        }
+
+        let invalid = [
+            serde_json::json!(["block"]),
+            serde_json::json!({"reason": "go on"}),
+            serde_json::json!({"decision": "approve", "reason": "go on"}),
+            serde_json::json!({"decision": "block", "reason": ""}),
+            serde_json::json!({"decision": "block", "reason": 1}),
+            serde_json::json!({"decision": "block", "reason": "go on", "continue": "yes"}),
+            serde_json::json!({"decision": "block", "reason": "go on", "stopReason": false}),
+            serde_json::json!({"decision": "block", "reason": "go on", "suppressOutput": 1}),
+            serde_json::json!({"decision": "block", "reason": "go on", "d": "block"}),
+        ];
+        for value in &invalid {
+            assert!(validate_hook_output(value).is_err(), "{}", value);
+        }
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn decisions_are_inserted_and_counted() {
+        let dir = scratch_dir("decisions-db");
+        let path = dir.join("decisions.db");
+        let logger = DebugLogger::new(false);
+        let block = Decision::Block("go on".to_string());
+
+        store_decision(&path, &InterventionRecord::new(Some("s1"), &block), &logger);
+        store_decision(&path, &InterventionRecord::new(Some("s1"), &block), &logger);
+        store_decision(&path, &InterventionRecord::new(Some("s2"), &Decision::Refusal), &logger);
+
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        let count = |decision: &str| -> i64 {
+            conn.query_row("SELECT COUNT(*) FROM decisions WHERE decision = ?1", [decision], |row| row.get(0))
+                .unwrap()
+        };
+        assert_eq!(count("block"), 2);
+        assert_eq!(count("refusal"), 1);
+        let reason: Option<String> = conn
+            .query_row("SELECT reason FROM decisions WHERE session_id = 's2'", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(reason, None);
+        drop(conn);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn selftest_samples_pass() {
+        for (name, result) in run_selftest_checks() {
+            assert_eq!(result, Ok(()), "{}", name);
+        }
+    }
+
+    #[test]
+    fn quiet_silences_the_debug_mirror() {
+        let args = Args::try_parse_from(["cc-goto-work", "--debug"]).unwrap();
+        assert!(args.debug_enabled());
+        let args = Args::try_parse_from(["cc-goto-work", "--debug", "--quiet"]).unwrap();
+        assert!(!args.debug_enabled());
+    }
+
+    #[test]
+    fn gzipped_transcripts_are_decompressed() {
+        use flate2::{write::GzEncoder, Compression};
+
+        let dir = scratch_dir("gzip");
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        write!(encoder, "{}\n{}\n", USER_LINE, MAX_TOKENS_LINE).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        // Detected by extension, and by magic bytes when the extension is missing
+        for name in ["transcript.jsonl.gz", "transcript.jsonl"] {
+            let path = dir.join(name);
+            fs::write(&path, &compressed).unwrap();
+            let lines = read_transcript_tail(&path, TAIL_READ_BYTES, TranscriptEncoding::Utf8).unwrap();
+            let raws: Vec<&str> = lines.iter().map(|l| l.raw.as_str()).collect();
+            assert_eq!(raws, [USER_LINE, MAX_TOKENS_LINE], "{}", name);
+            assert_eq!(last_stop_reason(&lines), Some("max_tokens"));
+            fs::remove_file(&path).unwrap();
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    fn utf16le(text: &str) -> Vec<u8> {
+        text.encode_utf16().flat_map(u16::to_le_bytes).collect()
+    }
+
+    #[test]
+    fn utf16le_tail_with_trailing_error_stays_aligned() {
+        let padding = format!("{{\"pad\":\"{}\"}}\n", "x".repeat(2000));
+        let tail = format!("{}\n{}\n", MAX_TOKENS_LINE, SAMPLE_ERROR);
+        let input = utf16le(&format!("{}{}", padding, tail));
+        // An odd window puts the start on the second byte of a code unit
+        let window = utf16le(&tail).len() as u64 + 3;
+        assert_eq!((input.len() as u64 - window) % 2, 1);
+
+        // Read the single window directly: widening would otherwise mask a misaligned start
+        let len = input.len() as u64;
+        let lines = read_window(&mut io::Cursor::new(input.clone()), len, window, TranscriptEncoding::Utf16le).unwrap();
+        let raws: Vec<&str> = lines.iter().map(|l| l.raw.as_str()).collect();
+        assert_eq!(raws, [MAX_TOKENS_LINE, SAMPLE_ERROR]);
+
+        let tail = read_seekable_tail(&mut io::Cursor::new(input), window, TranscriptEncoding::Utf16le).unwrap();
+        assert_eq!(tail.lines.len(), 2);
+        assert!(ends_with_error(&tail.lines));
+        assert_eq!(last_stop_reason(&tail.lines), Some("max_tokens"));
+    }
+
+    #[test]
+    fn latin1_tail_is_transcoded() {
+        let mut input = format!("{{\"pad\":\"{}\"}}\n", "x".repeat(2000)).into_bytes();
+        input.extend_from_slice(b"{\"type\":\"user\",\"message\":{\"content\":\"caf\xe9\"}}\n");
+        input.extend_from_slice(format!("{}\n", MAX_TOKENS_LINE).as_bytes());
+
+        let tail = read_seekable_tail(&mut io::Cursor::new(input), 1024, TranscriptEncoding::Latin1).unwrap();
+        assert_eq!(tail.lines.len(), 2);
+        assert_eq!(tail.lines[0].json.as_ref().unwrap()["message"]["content"], "café");
+        assert_eq!(last_stop_reason(&tail.lines), Some("max_tokens"));
+    }
+
+    #[test]
+    fn metrics_file_accumulates_counters_across_runs() {
+        let dir = scratch_dir("metrics");
+        let path = dir.join("cc_goto_work.prom");
+        let logger = DebugLogger::new(false);
+        let block = Decision::Block("go on".to_string());
+
+        update_metrics_file(&path, &InterventionRecord::new(Some("s"), &block), &logger);
+        update_metrics_file(&path, &InterventionRecord::new(Some("s"), &Decision::Refusal), &logger);
+        update_metrics_file(&path, &InterventionRecord::new(Some("s"), &block), &logger);
+
+        let text = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(lines.contains(&"# HELP cc_goto_work_decisions_total Stop hook invocations by decision."));
+        assert!(lines.contains(&"# TYPE cc_goto_work_decisions_total counter"));
+        assert!(lines.contains(&"# TYPE cc_goto_work_last_run_timestamp_seconds gauge"));
+        assert!(lines.contains(&r#"cc_goto_work_decisions_total{decision="block"} 2"#));
+        assert!(lines.contains(&r#"cc_goto_work_decisions_total{decision="refusal"} 1"#));
+        for line in lines.iter().filter(|l| !l.starts_with('#')) {
+            let (_, value) = line.rsplit_once(' ').unwrap();
+            assert!(value.parse::<u64>().is_ok(), "{}", line);
+        }
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1, "temp file left behind");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn recording_writes_an_input_and_tail_pair() {
+        let dir = scratch_dir("record");
+        let logger = DebugLogger::new(false);
+        let input = r#"{"session_id":"s","transcript_path":"/t.jsonl"}"#;
+        let lines = sample_lines(&[USER_LINE, MAX_TOKENS_LINE]);
+
+        record_invocation(&dir, input, &lines, &logger);
+
+        let input_path = dir.join(format!("{}-input.json", logger.run_id));
+        let tail_path = dir.join(format!("{}-tail.jsonl", logger.run_id));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        assert!(parse_hook_input(&fs::read_to_string(&input_path).unwrap()).is_ok());
+        let replayed = read_transcript_tail(&tail_path, TAIL_READ_BYTES, TranscriptEncoding::Utf8).unwrap();
+        let raws: Vec<&str> = replayed.iter().map(|l| l.raw.as_str()).collect();
+        assert_eq!(raws, [USER_LINE, MAX_TOKENS_LINE]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn doctor_passes_with_a_good_config_and_names_a_broken_one() {
+        let dir = scratch_dir("doctor");
+        let path = dir.join("config.yaml");
+        fs::write(&path, "providers:\n  - api_base: http://localhost\n    api_key: k\n    models: [m]\n").unwrap();
+
+        let args = Args::try_parse_from(["cc-goto-work", "-c", path.to_str().unwrap(), "doctor"]).unwrap();
+        let failed: Vec<&str> = doctor_checks(&args).into_iter().filter(|(_, r)| r.is_err()).map(|(n, _)| n).collect();
+        assert!(failed.is_empty(), "{:?}", failed);
+        assert_eq!(run_doctor(&args), 0);
+
+        fs::write(&path, "providers: []\n").unwrap();
+        let failed: Vec<&str> = doctor_checks(&args).into_iter().filter(|(_, r)| r.is_err()).map(|(n, _)| n).collect();
+        assert_eq!(failed, ["config"]);
+        assert_eq!(run_doctor(&args), 1);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn log_check_leaves_no_file_behind() {
+        let dir = scratch_dir("log-check");
+        assert!(check_log_writable(&dir.join(DEBUG_LOG_FILENAME)).is_ok());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        assert!(check_log_writable(&dir.join("missing").join(DEBUG_LOG_FILENAME)).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn dump_rows_report_parse_status_per_line() {
+        let rows = dump_rows(&sample_lines(&[USER_LINE, "{not json", MAX_TOKENS_LINE]));
+        assert_eq!(rows.len(), 3);
+        assert!(rows[0].starts_with("   0 json=ok  type=user stop_reason=- | "), "{}", rows[0]);
+        assert_eq!(rows[1], "   1 json=err | {not json");
+        assert!(rows[2].starts_with("   2 json=ok  type=assistant stop_reason=max_tokens | "), "{}", rows[2]);
+    }
+
+    #[test]
+    fn dump_lines_fails_on_a_missing_file() {
+        assert_eq!(run_dump_lines("/nonexistent/transcript.jsonl", TAIL_READ_BYTES, TranscriptEncoding::Utf8), 1);
+    }
+
+    #[test]
+    fn quoted_vote_summaries_are_not_interventions() {
+        let tool_result = r#"{"type":"user","message":{"content":[{"type":"tool_result","content":"{\"decision\":\"block\",\"reason\":\"vote: 2/3 continue, 1/3 stop\"}"}]}}"#;
+        let log_line = r#"{"timestamp":1,"session_id":"s","decision":"block","reason":"vote: 2/3 continue, 1/3 stop"}"#;
+        let block_feedback = r#"{"type":"user","message":{"content":[{"type":"text","text":"Stop hook feedback:\nAI: vote: 1/1 continue, 0/1 stop; cut off"}]}}"#;
+
+        let lines = sample_lines(&[USER_LINE, MAX_TOKENS_LINE, tool_result, log_line, tool_result, MAX_TOKENS_LINE]);
+        assert_eq!(count_prior_interventions(&lines), 0);
+
+        let lines = sample_lines(&[USER_LINE, SAMPLE_INTERVENTION, tool_result, block_feedback, MAX_TOKENS_LINE]);
+        assert_eq!(count_prior_interventions(&lines), 2);
+
+        let lines = sample_lines(&[SAMPLE_INTERVENTION, USER_LINE, MAX_TOKENS_LINE]);
+        assert_eq!(count_prior_interventions(&lines), 0);
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn transcript_paths_resolve_against_project_dir_then_cwd() {
+        // The only test touching CLAUDE_PROJECT_DIR, so its set/unset steps can't race
+        std::env::set_var("CLAUDE_PROJECT_DIR", "/proj");
+        assert_eq!(resolve_transcript_path("logs/t.jsonl", Some("/cwd")), PathBuf::from("/proj/logs/t.jsonl"));
+        assert_eq!(resolve_transcript_path("/abs/t.jsonl", Some("/cwd")), PathBuf::from("/abs/t.jsonl"));
+
+        std::env::set_var("CLAUDE_PROJECT_DIR", "");
+        assert_eq!(resolve_transcript_path("logs/t.jsonl", Some("/cwd")), PathBuf::from("/cwd/logs/t.jsonl"));
+
+        std::env::remove_var("CLAUDE_PROJECT_DIR");
+        assert_eq!(resolve_transcript_path("logs/t.jsonl", Some("/cwd")), PathBuf::from("/cwd/logs/t.jsonl"));
+        assert_eq!(resolve_transcript_path("logs/t.jsonl", None), PathBuf::from("logs/t.jsonl"));
+        assert_eq!(resolve_transcript_path("/abs/t.jsonl", Some("/cwd")), PathBuf::from("/abs/t.jsonl"));
+    }
+
+    #[test]
+    fn kill_switch_short_circuits_before_anything_else() {
+        // A test-only variable: setting CC_GOTO_WORK_DISABLE would leak into parallel tests
+        std::env::set_var("CC_GOTO_WORK_TEST_DISABLE", "1");
+        assert!(env_flag("CC_GOTO_WORK_TEST_DISABLE"));
+        assert!(!env_flag("CC_GOTO_WORK_TEST_DISABLE_UNSET"));
+
+        let logger = DebugLogger::new(false);
+        let disabled = DecisionPolicy {
+            disabled: env_flag("CC_GOTO_WORK_TEST_DISABLE"),
+            max_retries: Some(0),
+            once_per_turn: true,
+        };
+        let lines = sample_lines(&[USER_LINE, SAMPLE_REFUSAL]);
+        assert_eq!(short_circuit(&lines, true, &disabled, &logger), Some(Decision::Disabled));
+        assert_eq!(short_circuit(&[], false, &disabled, &logger), Some(Decision::Disabled));
+    }
+
+    #[test]
+    fn tail_window_starting_inside_a_multibyte_character() {
+        let cjk_user = r#"{"type":"user","message":{"content":"修复测试"}}"#;
+        let cjk_reply = r#"{"type":"assistant","message":{"content":[{"type":"text","text":"继续"}],"stop_reason":"max_tokens"}}"#;
+        let first = format!("{{\"pad\":\"{}\"}}\n", "字".repeat(500));
+        let input = format!("{}{}\n{}\n", first, cjk_user, cjk_reply).into_bytes();
+        let len = input.len() as u64;
+
+        // Start one byte into a 3-byte character of the first (partial) line
+        let start = first.len() - 2 * "字".len() - 1;
+        assert!(!first.is_char_boundary(start));
+        let lines = read_window(&mut io::Cursor::new(input.clone()), len, len - start as u64, TranscriptEncoding::Utf8)
+            .unwrap();
+        let raws: Vec<&str> = lines.iter().map(|l| l.raw.as_str()).collect();
+        assert_eq!(raws, [cjk_user, cjk_reply]);
+
+        // Start inside a character of the last line: it is dropped, then found again by widening
+        let window = (cjk_reply.len() - cjk_reply.find("继").unwrap() - 1) as u64;
+        let lines = read_window(&mut io::Cursor::new(input.clone()), len, window, TranscriptEncoding::Utf8).unwrap();
+        assert!(lines.is_empty());
+        let tail = read_seekable_tail(&mut io::Cursor::new(input), window, TranscriptEncoding::Utf8).unwrap();
+        let raws: Vec<&str> = tail.lines.iter().map(|l| l.raw.as_str()).collect();
+        assert_eq!(raws.last(), Some(&cjk_reply));
+        assert_eq!(last_stop_reason(&tail.lines), Some("max_tokens"));
+    }
+
+    #[test]
+    fn max_lines_must_be_positive() {
+        let dir = scratch_dir("max-lines");
+        let path = dir.join("config.yaml");
+        let base = "providers:\n  - api_base: http://localhost\n    api_key: k\n    models: [m]\n";
+
+        fs::write(&path, format!("{}max_lines: 0\n", base)).unwrap();
+        assert!(Config::load(&path).is_err());
+        fs::write(&path, format!("{}max_lines: 1\n", base)).unwrap();
+        assert_eq!(Config::load(&path).unwrap().max_lines, 1);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn seekable_tail_widens_until_a_stop_reason_is_visible() {
+        let padding: String = (0..200).map(|_| format!("{}\n", STREAMING_LINE)).collect();
+        let history: String = (0..1200).map(|_| format!("{}\n", USER_LINE)).collect();
+        let input = format!("{}{}\n{}\n{}", history, USER_LINE, MAX_TOKENS_LINE, padding);
+        let window = MIN_TAIL_READ_BYTES;
+        assert!(padding.len() as u64 > window);
+        assert!(history.len() > padding.len() * 2);
+        assert!((input.len() as u64) < MAX_TAIL_READ_BYTES);
+
+        let first = read_window(&mut io::Cursor::new(input.as_bytes()), input.len() as u64, window, TranscriptEncoding::Utf8)
+            .unwrap();
+        assert_eq!(last_stop_reason(&first), None);
+
+        let tail = read_seekable_tail(&mut io::Cursor::new(input.into_bytes()), window, TranscriptEncoding::Utf8).unwrap();
+        assert_eq!(last_stop_reason(&tail.lines), Some("max_tokens"));
+        assert!(!tail.complete);
+    }
+
+    #[test]
+    fn env_flags_are_read_leniently() {
+        for value in ["1", "true", "YES", " on "] {
+            assert!(is_truthy(value), "{:?}", value);
+        }
+        for value in ["", "0", "false", "off", "verbose"] {
+            assert!(!is_truthy(value), "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn seekable_tail_of_empty_source_is_empty() {
+        let tail = read_seekable_tail(&mut io::Cursor::new(Vec::new()), TAIL_READ_BYTES, TranscriptEncoding::Utf8).unwrap();
+        assert!(tail.lines.is_empty());
+        assert!(tail.complete);
+    }
+
+    #[test]
+    fn seekable_tail_smaller_than_window_keeps_every_line() {
+        let input = format!("{}\n{}\n", USER_LINE, MAX_TOKENS_LINE);
+        let tail = read_seekable_tail(&mut io::Cursor::new(input.into_bytes()), TAIL_READ_BYTES, TranscriptEncoding::Utf8)
+            .unwrap();
+        let raws: Vec<&str> = tail.lines.iter().map(|l| l.raw.as_str()).collect();
+        assert_eq!(raws, [USER_LINE, MAX_TOKENS_LINE]);
+        assert!(tail.complete);
+    }
+
+    #[test]
+    fn seekable_tail_drops_the_partial_first_line() {
+        let padding = format!("{{\"pad\":\"{}\"}}\n", "x".repeat(4000));
+        let input = format!("{}{}\n", padding, MAX_TOKENS_LINE);
+        let window = MAX_TOKENS_LINE.len() as u64 + 100;
+
+        let tail = read_seekable_tail(&mut io::Cursor::new(input.into_bytes()), window, TranscriptEncoding::Utf8).unwrap();
+        let raws: Vec<&str> = tail.lines.iter().map(|l| l.raw.as_str()).collect();
+        assert_eq!(raws, [MAX_TOKENS_LINE]);
+        assert!(!tail.complete);
+    }
+
+    #[test]
+    fn stream_tail_keeps_small_lines_after_an_oversized_line() {
+        let mut input = vec![b'x'; 200 * 1024];
+        input.push(b'\n');
+        for i in 0..10 {
+            input.extend_from_slice(format!("{{\"n\":{}}}\n", i).as_bytes());
+        }
+
+        let lines = read_stream_tail(io::Cursor::new(input), TAIL_READ_BYTES).lines;
+        assert_eq!(lines.len(), 10);
+        assert_eq!(lines[0].raw, r#"{"n":0}"#);
+    }
+
+    #[test]
+    fn stream_tail_survives_lossily_expanded_lines() {
+        let mut input = Vec::new();
+        for _ in 0..4 {
+            input.extend_from_slice(&[0xff; 2048]);
+            input.push(b'\n');
+        }
+        input.extend_from_slice(b"{\"n\":1}\n");
+
+        let lines = read_stream_tail(io::Cursor::new(input), 4096).lines;
+        assert_eq!(lines.last().map(|l| l.raw.as_str()), Some(r#"{"n":1}"#));
+    }
 }